@@ -0,0 +1,139 @@
+use std::fmt::Write;
+use std::io::Cursor;
+
+use anyhow::Result;
+use metaflac::block::{CueSheet, CueSheetTrack, CueSheetTrackIndex};
+use metaflac::{Block, BlockType, Tag as FlacTag};
+
+use crate::download::SOURCE_BITRATE_TAG;
+use crate::encoder::EncodedStream;
+use crate::quality::SourceQuality;
+use crate::track::{ArtistMetadata, TrackMetadata};
+
+/// The sample rate of the joined album, needed to turn sample offsets into cue sheet times.
+const SAMPLE_RATE: u64 = 44100;
+/// Cue sheet times count 75 frames per second.
+const CUE_FRAMES_PER_SECOND: u64 = 75;
+/// The track number of the lead-out track in cue sheets which don't come from a CD.
+const LEAD_OUT_TRACK: u8 = 255;
+/// `TRACK` numbers in cue files have two digits.
+const MAX_TRACKS: usize = 99;
+
+/// A track of an album which is written as a single file.
+pub struct AlbumTrack<'a> {
+    pub metadata: &'a TrackMetadata,
+    /// The first sample of the track, counted per channel, in the joined album.
+    pub offset: u64,
+    /// The source quality the track was fetched in.
+    pub quality: SourceQuality,
+}
+
+/// Makes sure an album of `count` tracks can be written as a single file.
+pub fn check_track_count(count: usize) -> Result<()> {
+    if count > MAX_TRACKS {
+        return Err(anyhow::anyhow!("A single file album can have at most {} tracks, this one has {}", MAX_TRACKS, count));
+    }
+    Ok(())
+}
+
+/// Adds the album's Vorbis comments, the per-track `CUE_TRACKnn_*` comments (including the
+/// source bitrate) and a `CUESHEET` block to the encoded flac stream.
+pub fn tag_stream(
+    tracks: &[AlbumTrack],
+    total_samples: u64,
+    file_name: &str,
+    stream: EncodedStream,
+) -> Result<EncodedStream> {
+    let first = tracks.first().ok_or(anyhow::anyhow!("An album needs at least one track"))?;
+    check_track_count(tracks.len())?;
+    let album = &first.metadata.album;
+
+    let mut reader = Cursor::new(&stream.stream);
+    let mut tag = FlacTag::read_from(&mut reader)?;
+
+    tag.set_vorbis("TITLE", vec![album.name.clone()]);
+    tag.set_vorbis("ALBUM", vec![album.name.clone()]);
+    tag.set_vorbis("ARTIST", vec![join_artists(&album.artists)]);
+    tag.set_vorbis("ALBUMARTIST", vec![join_artists(&album.artists)]);
+    if let Some(year) = album.year {
+        tag.set_vorbis("DATE", vec![year.to_string()]);
+    }
+    tag.set_vorbis("TRACKTOTAL", vec![tracks.len().to_string()]);
+
+    for (number, track) in (1..).zip(tracks) {
+        tag.set_vorbis(format!("CUE_TRACK{:02}_TITLE", number), vec![track.metadata.track_name.clone()]);
+        tag.set_vorbis(format!("CUE_TRACK{:02}_PERFORMER", number), vec![join_artists(&track.metadata.artists)]);
+        tag.set_vorbis(format!("CUE_TRACK{:02}_{}", number, SOURCE_BITRATE_TAG), vec![track.quality.kbps().to_string()]);
+    }
+
+    let mut cue_sheet = CueSheet::new();
+    // Spotify audio doesn't line up with CD sectors
+    cue_sheet.is_cd = false;
+    for (number, track) in (1..).zip(tracks) {
+        let mut cue_track = CueSheetTrack::new();
+        cue_track.offset = track.offset;
+        cue_track.number = number;
+        cue_track.indices.push(CueSheetTrackIndex { offset: 0, point_num: 1 });
+        cue_sheet.tracks.push(cue_track);
+    }
+    let mut lead_out = CueSheetTrack::new();
+    lead_out.offset = total_samples;
+    lead_out.number = LEAD_OUT_TRACK;
+    cue_sheet.tracks.push(lead_out);
+
+    tag.remove_blocks(BlockType::CueSheet);
+    tag.push_block(Block::CueSheet(cue_sheet));
+
+    tracing::debug!("Tagging single file album {} with {} tracks", file_name, tracks.len());
+
+    let mut tagged = Vec::with_capacity(stream.stream.len());
+    tag.write_to(&mut tagged)?;
+    tagged.extend_from_slice(&stream.stream[reader.position() as usize..]);
+
+    Ok(EncodedStream::new(tagged))
+}
+
+/// The external `.cue` file pointing to the album file.
+pub fn cue_sheet(tracks: &[AlbumTrack], file_name: &str) -> String {
+    let mut cue = String::new();
+
+    if let Some(first) = tracks.first() {
+        let album = &first.metadata.album;
+        if let Some(year) = album.year {
+            writeln!(cue, "REM DATE {}", year).unwrap();
+        }
+        writeln!(cue, "PERFORMER \"{}\"", quote(&join_artists(&album.artists))).unwrap();
+        writeln!(cue, "TITLE \"{}\"", quote(&album.name)).unwrap();
+    }
+    writeln!(cue, "FILE \"{}\" WAVE", quote(file_name)).unwrap();
+
+    for (number, track) in (1..).zip(tracks) {
+        writeln!(cue, "  TRACK {:02} AUDIO", number).unwrap();
+        writeln!(cue, "    TITLE \"{}\"", quote(&track.metadata.track_name)).unwrap();
+        writeln!(cue, "    PERFORMER \"{}\"", quote(&join_artists(&track.metadata.artists))).unwrap();
+        writeln!(cue, "    INDEX 01 {}", cue_time(track.offset)).unwrap();
+    }
+
+    cue
+}
+
+/// Formats a sample offset as `mm:ss:ff`.
+fn cue_time(offset: u64) -> String {
+    let frames = offset * CUE_FRAMES_PER_SECOND / SAMPLE_RATE;
+    let seconds = frames / CUE_FRAMES_PER_SECOND;
+
+    format!("{:02}:{:02}:{:02}", seconds / 60, seconds % 60, frames % CUE_FRAMES_PER_SECOND)
+}
+
+/// Cue files can't escape double quotes inside strings.
+fn quote(value: &str) -> String {
+    value.replace('"', "'").replace(['\r', '\n'], " ")
+}
+
+fn join_artists(artists: &[ArtistMetadata]) -> String {
+    artists
+        .iter()
+        .map(|artist| artist.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
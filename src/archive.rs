@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Component, Path};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_compression::tokio::write::GzipEncoder;
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipDateTimeBuilder, ZipEntryBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use crate::storage::civil_date;
+
+type Output = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// The name of the cover of a folder holding the tracks of a single album.
+pub(crate) const COVER_FILE_NAME: &str = "cover.jpg";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// Stored without compression, the audio is compressed already.
+    #[default]
+    Zip,
+    /// A gzip compressed tar.
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+}
+
+enum Writer {
+    Zip(ZipFileWriter<Output>),
+    TarGz(tokio_tar::Builder<GzipEncoder<Output>>),
+    Finished,
+}
+
+struct State {
+    writer: Writer,
+    names: HashSet<String>,
+}
+
+/// Bundles the files of a job into a single ZIP or tar.gz, streaming every track, cue sheet
+/// and playlist file into it as soon as it's written. The album covers are added when the
+/// job ends, they aren't written to the storage.
+///
+/// Set it as `DownloadOptions::archive`, `download_tracks` and `sync_playlist` finish the
+/// archive when the job ends, also when it fails. Clones share the archive.
+#[derive(Clone)]
+pub struct Archive {
+    format: ArchiveFormat,
+    state: Arc<Mutex<State>>,
+}
+
+impl fmt::Debug for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Archive").field("format", &self.format).finish_non_exhaustive()
+    }
+}
+
+impl Archive {
+    /// Streams the archive into the writer, for example the body of an http response.
+    pub fn new<W: AsyncWrite + Send + Unpin + 'static>(writer: W, format: ArchiveFormat) -> Self {
+        let output: Output = Box::new(writer);
+        let writer = match format {
+            ArchiveFormat::Zip => Writer::Zip(ZipFileWriter::with_tokio(output)),
+            ArchiveFormat::TarGz => Writer::TarGz(tokio_tar::Builder::new(GzipEncoder::new(output))),
+        };
+
+        Archive {
+            format,
+            state: Arc::new(Mutex::new(State {
+                writer,
+                names: HashSet::new(),
+            })),
+        }
+    }
+
+    /// Writes the archive to a file, replacing an existing one.
+    pub async fn create<P: AsRef<Path>>(path: P, format: ArchiveFormat) -> Result<Self> {
+        if let Some(parent) = path.as_ref().parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = tokio::fs::File::create(path).await?;
+        Ok(Archive::new(file, format))
+    }
+
+    pub fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    /// Whether the file was added to the archive, at its path relative to `root`.
+    pub(crate) async fn contains(&self, root: &Path, path: &Path) -> bool {
+        match entry_name(root, path) {
+            Ok(name) => self.state.lock().await.names.contains(&name),
+            Err(_) => false,
+        }
+    }
+
+    /// Adds a file to the archive, at its path relative to `root`. A file which is already in
+    /// the archive isn't added again.
+    pub(crate) async fn add(&self, root: &Path, path: &Path, content: &[u8]) -> Result<()> {
+        let name = entry_name(root, path)?;
+        let mut state = self.state.lock().await;
+        if !state.names.insert(name.clone()) {
+            tracing::debug!("{} is in the archive already", name);
+            return Ok(());
+        }
+
+        tracing::debug!("Adding {} to the archive", name);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        match &mut state.writer {
+            Writer::Zip(writer) => {
+                let entry = ZipEntryBuilder::new(name.into(), Compression::Stored)
+                    .unix_permissions(0o644)
+                    .last_modification_date(zip_date(now));
+                writer.write_entry_whole(entry, content).await?;
+            }
+            Writer::TarGz(builder) => {
+                let mut header = tokio_tar::Header::new_gnu();
+                header.set_size(content.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(now);
+                builder.append_data(&mut header, name, content).await?;
+            }
+            Writer::Finished => return Err(anyhow::anyhow!("The archive is finished already")),
+        }
+
+        Ok(())
+    }
+
+    /// Writes the end of the archive and flushes it. Finishing it again does nothing.
+    pub async fn finish(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        match std::mem::replace(&mut state.writer, Writer::Finished) {
+            Writer::Zip(writer) => {
+                let mut output = writer.close().await?.into_inner();
+                output.shutdown().await?;
+            }
+            Writer::TarGz(builder) => {
+                let mut encoder = builder.into_inner().await?;
+                // Writes the gzip trailer and shuts the output down
+                encoder.shutdown().await?;
+            }
+            Writer::Finished => {}
+        }
+
+        tracing::info!("Finished the archive with {} files", state.names.len());
+        Ok(())
+    }
+}
+
+/// The path of the file relative to `root` with `/` as separator, or its file name if it isn't
+/// below `root`.
+fn entry_name(root: &Path, path: &Path) -> Result<String> {
+    let relative = path.strip_prefix(root).unwrap_or_else(|_| Path::new(path.file_name().unwrap_or_default()));
+
+    let segments: Vec<String> = relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => Some(segment.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    if segments.is_empty() {
+        return Err(anyhow::anyhow!("{:?} can't be added to the archive", path));
+    }
+
+    Ok(segments.join("/"))
+}
+
+fn zip_date(seconds: u64) -> async_zip::ZipDateTime {
+    let (year, month, day) = civil_date((seconds / 86400) as i64);
+    let time_of_day = seconds % 86400;
+
+    ZipDateTimeBuilder::new()
+        .year(year as i32)
+        .month(month)
+        .day(day)
+        .hour((time_of_day / 3600) as u32)
+        .minute((time_of_day % 3600 / 60) as u32)
+        .second((time_of_day % 60) as u32)
+        .build()
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::io::Cursor;
+use std::time::Duration;
+
+use anyhow::Result;
+use ogg::PacketReader;
+use serde::{Deserialize, Serialize};
+
+use crate::track::TrackMetadata;
+
+const SAMPLE_RATE: u64 = 44100;
+const CHANNELS: usize = 2;
+/// Consecutive full scale samples on a channel which count as a clipped run.
+const CLIPPED_RUN_LENGTH: usize = 3;
+
+/// What happens to a track whose decoded audio looks broken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCheckAction {
+    /// The track is downloaded again, and marked suspect if it still looks broken. Clipping
+    /// is part of the decoded stream, so clipped tracks are only marked.
+    #[default]
+    Retry,
+    /// The track is kept and marked suspect in the report.
+    Mark,
+}
+
+/// Verifies the decoded audio of every track before it's written.
+///
+/// Original Ogg files are only checked for truncation, they aren't decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioCheck {
+    pub enabled: bool,
+    pub action: AudioCheckAction,
+    /// How often a broken track is downloaded again with `AudioCheckAction::Retry`.
+    pub retries: u32,
+    /// Audio missing from the end of a track before it counts as truncated, 2 seconds by default.
+    pub max_missing: Duration,
+    /// The longest digital silence allowed within a track, not counting silence at its start
+    /// or end, 2 seconds by default.
+    pub max_silence: Duration,
+    /// Runs of full scale samples allowed before a track counts as clipping.
+    pub max_clipped_runs: usize,
+}
+
+impl Default for AudioCheck {
+    fn default() -> Self {
+        AudioCheck {
+            enabled: true,
+            action: AudioCheckAction::default(),
+            retries: 1,
+            max_missing: Duration::from_secs(2),
+            max_silence: Duration::from_secs(2),
+            max_clipped_runs: 50,
+        }
+    }
+}
+
+/// A problem found in the decoded audio of a track.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "issue", rename_all = "lowercase")]
+pub enum AudioIssue {
+    /// The audio is shorter than the track's duration.
+    Truncated { expected_ms: u64, actual_ms: u64 },
+    /// Digital silence within the track.
+    Silence { at_ms: u64, length_ms: u64 },
+    Clipping { runs: usize },
+}
+
+impl AudioIssue {
+    /// Whether downloading the track again may fix the issue.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, AudioIssue::Truncated { .. } | AudioIssue::Silence { .. })
+    }
+}
+
+impl fmt::Display for AudioIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioIssue::Truncated { expected_ms, actual_ms } => {
+                write!(f, "truncated to {} of {} ms", actual_ms, expected_ms)
+            }
+            AudioIssue::Silence { at_ms, length_ms } => write!(f, "{} ms of silence at {} ms", length_ms, at_ms),
+            AudioIssue::Clipping { runs } => write!(f, "{} clipped runs", runs),
+        }
+    }
+}
+
+impl AudioCheck {
+    /// Checks interleaved 16 bit stereo samples decoded from the track.
+    pub fn check_samples(&self, samples: &[i32], metadata: &TrackMetadata) -> Vec<AudioIssue> {
+        if !self.enabled {
+            return Vec::new();
+        }
+
+        let frames = samples.len() / CHANNELS;
+        let mut issues: Vec<AudioIssue> = self.check_length(frames as u64, metadata).into_iter().collect();
+        issues.extend(self.check_silence(samples));
+        issues.extend(self.check_clipping(samples));
+        issues
+    }
+
+    /// Checks the length of an Ogg Vorbis stream from the granule position of its last page.
+    pub fn check_ogg(&self, stream: &[u8], metadata: &TrackMetadata) -> Result<Vec<AudioIssue>> {
+        if !self.enabled {
+            return Ok(Vec::new());
+        }
+
+        let mut reader = PacketReader::new(Cursor::new(stream));
+        let mut frames = 0;
+        while let Some(packet) = reader.read_packet()? {
+            // Pages on which no packet ends have no granule position
+            if packet.absgp_page() != u64::MAX {
+                frames = frames.max(packet.absgp_page());
+            }
+        }
+
+        Ok(self.check_length(frames, metadata).into_iter().collect())
+    }
+
+    fn check_length(&self, frames: u64, metadata: &TrackMetadata) -> Option<AudioIssue> {
+        let expected_ms = metadata.duration.max(0) as u64;
+        let actual_ms = frames * 1000 / SAMPLE_RATE;
+
+        (actual_ms + (self.max_missing.as_millis() as u64) < expected_ms)
+            .then_some(AudioIssue::Truncated { expected_ms, actual_ms })
+    }
+
+    fn check_silence(&self, samples: &[i32]) -> Vec<AudioIssue> {
+        let frames = || samples.chunks_exact(CHANNELS);
+        let max_frames = self.max_silence.as_millis() as usize * SAMPLE_RATE as usize / 1000;
+
+        // Silence at the start or end of a track is part of many recordings
+        let Some(first) = frames().position(|frame| frame.iter().any(|sample| *sample != 0)) else {
+            return Vec::new();
+        };
+        let last = frames().rposition(|frame| frame.iter().any(|sample| *sample != 0)).unwrap_or(first);
+
+        let mut issues = Vec::new();
+        let mut run_start = None;
+        for (index, frame) in frames().enumerate().take(last + 1).skip(first) {
+            let silent = frame.iter().all(|sample| *sample == 0);
+            match (silent, run_start) {
+                (true, None) => run_start = Some(index),
+                (false, Some(start)) => {
+                    if index - start > max_frames {
+                        issues.push(AudioIssue::Silence {
+                            at_ms: frames_to_ms(start),
+                            length_ms: frames_to_ms(index - start),
+                        });
+                    }
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+
+        issues
+    }
+
+    fn check_clipping(&self, samples: &[i32]) -> Option<AudioIssue> {
+        let full_scale = |sample: i32| sample >= i32::from(i16::MAX) || sample <= i32::from(i16::MIN);
+
+        let mut runs = 0;
+        for channel in 0..CHANNELS {
+            let mut length = 0;
+            for sample in samples.iter().skip(channel).step_by(CHANNELS) {
+                if full_scale(*sample) {
+                    length += 1;
+                    if length == CLIPPED_RUN_LENGTH {
+                        runs += 1;
+                    }
+                } else {
+                    length = 0;
+                }
+            }
+        }
+
+        (runs > self.max_clipped_runs).then_some(AudioIssue::Clipping { runs })
+    }
+
+    /// Whether a track with these issues is downloaded again, after `attempt` retries.
+    pub fn should_retry(&self, issues: &[AudioIssue], attempt: u32) -> bool {
+        issues.iter().any(AudioIssue::is_transient) && self.action == AudioCheckAction::Retry && attempt < self.retries
+    }
+}
+
+fn frames_to_ms(frames: usize) -> u64 {
+    frames as u64 * 1000 / SAMPLE_RATE
+}
@@ -3,6 +3,10 @@ use librespot::playback::audio_backend::SinkError;
 use librespot::playback::convert::Converter;
 use librespot::playback::decoder::AudioPacket;
 
+use std::time::Duration;
+
+use crate::job::PauseGate;
+use crate::pacing::Throttle;
 use crate::track::TrackMetadata;
 
 pub enum SinkEvent {
@@ -15,11 +19,13 @@ pub struct ChannelSink {
     sender: tokio::sync::mpsc::UnboundedSender<SinkEvent>,
     bytes_total: usize,
     bytes_sent: usize,
+    pause: PauseGate,
+    throttle: Option<Throttle>,
 }
 
 impl ChannelSink {
 
-    pub fn new(track: TrackMetadata) -> (Self, SinkEventChannel) {
+    pub fn new(track: TrackMetadata, pause: PauseGate, throttle: Option<Throttle>) -> (Self, SinkEventChannel) {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
 
         (
@@ -27,6 +33,8 @@ impl ChannelSink {
                 sender: tx,
                 bytes_sent: 0,
                 bytes_total: Self::convert_track_duration_to_size(&track),
+                pause,
+                throttle,
             },
             rx,
         )
@@ -61,11 +69,19 @@ impl Sink for ChannelSink {
     }
 
     fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> Result<(), SinkError> {
+        // Holds the player's decoding while the job is paused
+        self.pause.wait_blocking();
+
         let data = converter.f64_to_s16(
             packet
                 .samples()
                 .map_err(|_| SinkError::OnWrite("Failed to get samples".to_string()))?,
         );
+        if let Some(throttle) = &self.throttle {
+            // Interleaved stereo at 44.1kHz
+            throttle.wait_blocking(Duration::from_secs_f64(data.len() as f64 / (44100.0 * 2.0)));
+        }
+
         let data32: Vec<i32> = data.iter().map(|el| i32::from(*el)).collect();
         self.bytes_sent += data32.len() * std::mem::size_of::<i32>();
 
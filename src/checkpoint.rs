@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::download::DownloadOptions;
+use crate::report::{TrackReport, TrackStatus};
+use crate::track::Track;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CheckpointTrack {
+    #[serde(flatten)]
+    track: Track,
+    /// `None` while the track still has to be downloaded.
+    status: Option<TrackStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CheckpointData {
+    options: DownloadOptions,
+    tracks: Vec<CheckpointTrack>,
+}
+
+/// The resolved tracks, options and per-track status of a job, saved to a state file
+/// after every finished track so an interrupted job can be resumed.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    data: CheckpointData,
+}
+
+impl Checkpoint {
+    pub async fn create<P: AsRef<Path>>(path: P, options: &DownloadOptions, tracks: &[Track]) -> Result<Self> {
+        let checkpoint = Checkpoint {
+            path: path.as_ref().to_path_buf(),
+            data: CheckpointData {
+                options: options.clone(),
+                tracks: tracks
+                    .iter()
+                    .map(|track| CheckpointTrack { track: track.clone(), status: None })
+                    .collect(),
+            },
+        };
+        checkpoint.save().await?;
+
+        Ok(checkpoint)
+    }
+
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = tokio::fs::read(path.as_ref()).await?;
+
+        Ok(Checkpoint {
+            path: path.as_ref().to_path_buf(),
+            data: serde_json::from_slice(&content)?,
+        })
+    }
+
+    /// The options of the job, pointing to this checkpoint's state file.
+    pub fn options(&self) -> DownloadOptions {
+        let mut options = self.data.options.clone();
+        options.state_file = Some(self.path.clone());
+        options
+    }
+
+    /// Every track of the job, in their original order.
+    pub fn tracks(&self) -> Vec<Track> {
+        self.data.tracks.iter().map(|track| track.track.clone()).collect()
+    }
+
+    /// Whether the track at this index of `tracks` was downloaded or skipped already.
+    pub fn is_finished(&self, index: usize) -> bool {
+        self.data.tracks[index].status.as_ref().is_some_and(TrackStatus::is_finished)
+    }
+
+    /// The tracks which weren't downloaded or skipped yet, including the cancelled ones.
+    pub fn unfinished(&self) -> Vec<Track> {
+        self.data
+            .tracks
+            .iter()
+            .filter(|track| !track.status.as_ref().is_some_and(TrackStatus::is_finished))
+            .map(|track| track.track.clone())
+            .collect()
+    }
+
+    /// Records the outcome of a track and saves the state file.
+    pub async fn update(&mut self, track: &Track, report: &TrackReport) -> Result<()> {
+        let entry = self.data.tracks.iter_mut().find(|entry| {
+            !entry.status.as_ref().is_some_and(TrackStatus::is_finished)
+                && entry.track.id == track.id
+                && entry.track.playlist_id == track.playlist_id
+                && entry.track.album_id == track.album_id
+        });
+
+        if let Some(entry) = entry {
+            entry.status = Some(report.status.clone());
+        }
+
+        self.save().await
+    }
+
+    /// Removes the state file once every track of the job was downloaded or skipped.
+    pub async fn finish(&self) -> Result<()> {
+        if self.unfinished().is_empty() {
+            tokio::fs::remove_file(&self.path).await?;
+        }
+        Ok(())
+    }
+
+    async fn save(&self) -> Result<()> {
+        // Write to a temporary file first so a crash never leaves a truncated state file behind
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&self.data)?).await?;
+        tokio::fs::rename(tmp, &self.path).await?;
+        Ok(())
+    }
+}
@@ -1,9 +1,12 @@
+use std::collections::{hash_map, BTreeMap, HashMap};
 use std::fmt::Write;
+use std::io::Cursor;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::anyhow;
 use anyhow::Result;
 use futures::StreamExt;
 use futures::TryStreamExt;
@@ -13,7 +16,7 @@ use indicatif::ProgressBar;
 use indicatif::ProgressState;
 use indicatif::ProgressStyle;
 use librespot::core::session::Session;
-use librespot::core::spotify_id::SpotifyId;
+use librespot::core::spotify_id::{FileId, SpotifyAudioType};
 use librespot::metadata::Metadata;
 use librespot::playback::config::PlayerConfig;
 use librespot::playback::mixer::NoOpVolume;
@@ -21,39 +24,212 @@ use librespot::playback::mixer::VolumeGetter;
 use librespot::playback::player::Player;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 use id3::{Tag as id3Tag, Version};
+use id3::frame::ExtendedText;
 use metaflac::Tag as FlacTag;
 
 
 use serde::{Serialize, Deserialize};
 
+use crate::album_file;
+use crate::archive::{Archive, COVER_FILE_NAME};
+use crate::hooks::{HookMetadata, HookPayload, Hooks};
+use crate::album_file::AlbumTrack;
 use crate::channel_sink::ChannelSink;
+use crate::encoder::EncoderSettings;
+use crate::encoder::EncodedStream;
 use crate::encoder::Format;
 use crate::encoder::Samples;
+use crate::ogg_file;
+use crate::ogg_file::OriginalFile;
 use crate::channel_sink::SinkEvent;
 use crate::track::ArtistMetadata;
 use crate::track::Track;
 use crate::track::TrackMetadata;
+use crate::sanitize::Sanitizer;
+use crate::template;
+use crate::template::PathTemplates;
+use crate::template::TemplateValues;
+use crate::manifest::Manifest;
+use crate::manifest::ManifestEntry;
+use crate::manifest::ManifestSource;
+use crate::checkpoint::Checkpoint;
+use crate::memory::{MemoryBudget, MemoryGate};
+use crate::memory;
+use crate::pacing::Pacer;
+use crate::watchdog::{Attempt, Outcome, Stall, Watchdog};
+use crate::audio_check::{AudioCheck, AudioIssue};
+use crate::pacing::Pacing;
+use crate::pacing::Throttle;
+use crate::plan;
+use crate::space;
+use crate::storage::Storage;
+use crate::space::{SpaceAction, SpaceCheck, SpaceEstimate};
+use crate::quality;
+use crate::quality::SourceQuality;
+use crate::plan::CollisionPolicy;
+use crate::plan::JobPlan;
+use crate::plan::PlanAction;
+use crate::plan::PlanEntry;
+use crate::plan::PlannedTrack;
+use crate::playlist_file;
+use crate::playlist_file::PlaylistFiles;
+use crate::job::JobHandle;
+use crate::job::JobState;
+use crate::queue::{Permit, QueueSlot, QueuedTrackState};
+use crate::report::JobReport;
+use crate::report::TrackReport;
+use crate::report::TrackStatus;
 use crate::DownloadState;
 
 
+/// The tag holding the kbps of the stream a file was made from.
+pub const SOURCE_BITRATE_TAG: &str = "SOURCE_BITRATE";
+
+/// How long fetching an album cover for the archive may take.
+const COVER_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct Downloader<'a> {
     player_config: PlayerConfig,
     session: &'a Session,
     progress_bar: MultiProgress,
-    state: Arc<Mutex<DownloadState>>
+    state: Arc<Mutex<DownloadState>>,
+    manifest: Arc<Mutex<Manifest>>,
+    pacer: Arc<Pacer>,
+    storage: Arc<dyn Storage>,
+    queue: Option<QueueSlot>,
+}
+
+/// A further format every track is written in, encoded from the same decoded samples as the
+/// job's `format`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputFormat {
+    pub format: Format,
+    pub settings: EncoderSettings,
+    /// Path templates of this format. Without them the file goes next to the track's main
+    /// file, with the format's extension.
+    pub templates: Option<PathTemplates>,
+}
+
+impl OutputFormat {
+    pub fn new(format: Format) -> Self {
+        OutputFormat {
+            format,
+            settings: EncoderSettings::new(format, None),
+            templates: None,
+        }
+    }
+
+    pub fn with_settings(mut self, settings: EncoderSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn with_templates(mut self, templates: PathTemplates) -> Self {
+        self.templates = Some(templates);
+        self
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DownloadOptions {
     pub destination: PathBuf,
-    #[allow(dead_code)]
     pub compression: Option<u32>,
     pub parallel: usize,
     pub format: Format,
+    pub templates: PathTemplates,
+    pub sanitizer: Sanitizer,
+    /// How output paths claimed by more than one track are resolved.
+    #[serde(default)]
+    pub collisions: CollisionPolicy,
+    /// Leaves out tracks whose output file exists in the storage already, or which the manifest
+    /// knows in the same format at a path which still exists.
+    #[serde(default)]
+    pub skip_existing: bool,
+    /// The playlist files written for every playlist and album, an `.m3u8` by default.
+    #[serde(default)]
+    pub playlist_files: PlaylistFiles,
+    /// Writes every album as a single flac with an embedded cue sheet instead of a file per track,
+    /// using the `album_file` path template.
+    #[serde(default)]
+    pub single_file_albums: bool,
+    /// Formats every track is written in besides `format`, without fetching or decoding it
+    /// again. They can't be combined with `Ogg` or single file albums.
+    #[serde(default)]
+    pub extra_formats: Vec<OutputFormat>,
+    /// Delays, bandwidth cap and quotas which keep the job from fetching tracks back to back.
+    #[serde(default)]
+    pub pacing: Pacing,
+    /// The preferred bitrate of the fetched stream, the next best one is used when a track
+    /// isn't available in it.
+    #[serde(default)]
+    pub quality: SourceQuality,
+    /// Compares the estimated size of the job with the free space at the destination before it starts.
+    #[serde(default)]
+    pub space_check: SpaceCheck,
+    /// Only resolves the job and returns its plan in the `JobReport`, without fetching any audio.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Runs as many tracks at once as their estimated buffers fit into, up to the budget's
+    /// `max_parallel`, instead of `parallel` tracks.
+    #[serde(default)]
+    pub memory_budget: Option<MemoryBudget>,
+    /// Tears down and retries tracks whose player stops delivering audio or takes too long.
+    #[serde(default)]
+    pub watchdog: Watchdog,
+    /// Verifies the decoded audio of every track for truncation, silent gaps and clipping.
+    #[serde(default)]
+    pub audio_check: AudioCheck,
+    /// Also streams every finished track, cue sheet and playlist file of the job into this
+    /// archive, which is finished when `download_tracks` or `sync_playlist` returns. It can't
+    /// be combined with `state_file`.
+    #[serde(skip)]
+    pub archive: Option<Archive>,
+    /// Post-processing which runs after every track and after the job.
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Saves the job's progress to this file so it can be resumed with `SpotifyDownloader::resume`.
+    pub state_file: Option<PathBuf>,
+    #[serde(skip)]
+    pub job: JobHandle,
 }
 
 impl DownloadOptions {
+    /// The format of the main file followed by the extra formats.
+    pub(crate) fn formats(&self) -> impl Iterator<Item = Format> + '_ {
+        std::iter::once(self.format).chain(self.extra_formats.iter().map(|output| output.format))
+    }
+
+    /// The formats along with the settings they are encoded with.
+    fn encodings(&self) -> impl Iterator<Item = (Format, EncoderSettings)> + '_ {
+        std::iter::once((self.format, EncoderSettings::new(self.format, self.compression)))
+            .chain(self.extra_formats.iter().map(|output| (output.format, output.settings)))
+    }
+
+    /// Makes sure every extra format can be encoded from the decoded samples and gets a path
+    /// of its own.
+    fn check_formats(&self) -> Result<()> {
+        if self.extra_formats.is_empty() {
+            return Ok(());
+        }
+        if self.formats().any(|format| format == Format::Ogg) {
+            return Err(anyhow::anyhow!("Ogg is passed through without decoding, it can't be combined with other formats"));
+        }
+        if self.single_file_albums {
+            return Err(anyhow::anyhow!("Single file albums can't be written in extra formats"));
+        }
+
+        let mut next_to_main = vec![self.format];
+        for output in self.extra_formats.iter().filter(|output| output.templates.is_none()) {
+            if next_to_main.contains(&output.format) {
+                return Err(anyhow::anyhow!("{} is written to the same path twice", output.format.extension()));
+            }
+            next_to_main.push(output.format);
+        }
+        Ok(())
+    }
+
     pub fn new(destination: Option<&str>, compression: Option<u32>, parallel: usize, format: Format) -> Self {
         let destination =
             destination.map_or_else(|| std::env::current_dir().unwrap(), PathBuf::from);
@@ -61,7 +237,25 @@ impl DownloadOptions {
             destination,
             compression,
             parallel,
-            format
+            format,
+            templates: PathTemplates::default(),
+            sanitizer: Sanitizer::default(),
+            collisions: CollisionPolicy::default(),
+            skip_existing: false,
+            playlist_files: PlaylistFiles::default(),
+            single_file_albums: false,
+            extra_formats: Vec::new(),
+            pacing: Pacing::default(),
+            quality: SourceQuality::default(),
+            space_check: SpaceCheck::default(),
+            dry_run: false,
+            memory_budget: None,
+            watchdog: Watchdog::default(),
+            audio_check: AudioCheck::default(),
+            archive: None,
+            hooks: Hooks::default(),
+            state_file: None,
+            job: JobHandle::new(),
         }
     }
 }
@@ -81,121 +275,984 @@ enum Action {
     },
     Downloaded {
         file_name: String
+    },
+    Cancelled {
+        file_name: String
+    },
+    Failed {
+        file_name: String,
+        reason: String
+    },
+    Paused {
+        file_name: String
+    },
+    Job {
+        state: JobState
+    }
+}
+
+/// A unit of a job which is downloaded on its own.
+enum Work {
+    Track(Box<PlannedTrack>),
+    /// The tracks of an album which is written as a single file.
+    Album(Vec<PlannedTrack>),
+}
+
+impl Work {
+    fn planned(&self) -> Vec<&PlannedTrack> {
+        match self {
+            Work::Track(planned) => vec![planned],
+            Work::Album(planned) => planned.iter().collect(),
+        }
+    }
+
+    /// The estimated memory the unit buffers while it runs. An album keeps the samples of
+    /// every track until it's encoded.
+    fn memory(&self, options: &DownloadOptions) -> u64 {
+        self.planned()
+            .into_iter()
+            .filter(|planned| !planned.is_skipped())
+            .map(|planned| {
+                // Every extra format holds its encoded file as well, and all but the last
+                // format are encoded from a copy of the samples, one at a time
+                let mut encodings = options.encodings();
+                let main = encodings
+                    .next()
+                    .map(|(format, settings)| memory::estimate_track_memory(&planned.metadata, format, settings, options.quality))
+                    .unwrap_or_default();
+                let mut extra: u64 = encodings
+                    .map(|(format, settings)| space::estimate_track_size(&planned.metadata, format, settings, options.quality))
+                    .sum();
+                if !options.extra_formats.is_empty() {
+                    extra += memory::estimate_samples_memory(&planned.metadata);
+                }
+                main + extra
+            })
+            .sum()
+    }
+}
+
+/// The progress bar and progress events of a single download.
+struct Progress {
+    pb: ProgressBar,
+    message: Arc<Mutex<Action>>,
+    stop_flag: Arc<Mutex<bool>>,
+    file_name: String,
+}
+
+impl Progress {
+    async fn set(&self, pb_message: String, action: Action) {
+        self.pb.set_message(pb_message);
+        *self.message.lock().await = action;
+    }
+
+    /// Sends the last progress event and stops the reporter.
+    async fn stop(&self, action: Action) {
+        let mut msg = self.message.lock().await;
+        let mut stop_flag = self.stop_flag.lock().await;
+        *msg = action;
+        *stop_flag = true;
+    }
+
+    async fn finish(&self) {
+        self.pb.finish_with_message(format!("Downloaded {}", &self.file_name));
+        self.stop(Action::Downloaded { file_name: self.file_name.clone() }).await;
     }
 }
 
 impl<'a> Downloader<'a> {
-    pub fn new(session: &'a Session, state: Arc<Mutex<DownloadState>>) -> Self {
+    pub fn new(
+        session: &'a Session,
+        state: Arc<Mutex<DownloadState>>,
+        manifest: Arc<Mutex<Manifest>>,
+        pacer: Arc<Pacer>,
+        storage: Arc<dyn Storage>,
+    ) -> Self {
         Downloader {
             player_config: PlayerConfig::default(),
             session,
             progress_bar: MultiProgress::new(),
             state,
+            manifest,
+            pacer,
+            storage,
+            queue: None,
         }
     }
 
+    /// Makes the tracks of this downloader's job wait for a free slot in the queue.
+    pub(crate) fn in_queue(mut self, slot: QueueSlot) -> Self {
+        self.queue = Some(slot);
+        self
+    }
+
+    pub fn manifest(&self) -> &Arc<Mutex<Manifest>> {
+        &self.manifest
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
     pub async fn download_tracks(
-        self,
+        &self,
         tracks: Vec<Track>,
         options: &DownloadOptions,
+    ) -> Result<JobReport> {
+        let report = self.download_job(tracks, options).await;
+        self.close_archive(report, options).await
+    }
+
+    async fn download_job(&self, tracks: Vec<Track>, options: &DownloadOptions) -> Result<JobReport> {
+        if options.dry_run {
+            let plan = self.dry_run(tracks, options).await?;
+            return Ok(JobReport {
+                plan: Some(plan),
+                ..JobReport::default()
+            });
+        }
+
+        let planned = self.plan_tracks(tracks, options).await?;
+        let report = self.download_planned(planned.clone(), options).await?;
+        self.write_playlist_files(&planned, options).await?;
+
+        let skipped = planned
+            .iter()
+            .filter(|planned| planned.is_skipped())
+            .map(|planned| planned.path.clone())
+            .collect();
+        self.finish_archive(&planned, skipped, &report, options).await?;
+        self.run_job_hooks(&report, options).await?;
+
+        Ok(report)
+    }
+
+    /// Adds the files the job didn't write itself and the album covers to the archive, so it
+    /// holds the whole job, and finishes it. `existing` are the paths of tracks which were
+    /// already there, the tracks skipped because they exist are taken from the report.
+    pub(crate) async fn finish_archive(
+        &self,
+        planned: &[PlannedTrack],
+        existing: Vec<PathBuf>,
+        report: &JobReport,
+        options: &DownloadOptions,
     ) -> Result<()> {
-        futures::stream::iter(tracks)
-            .map(|track| {
-                self.download_track(track, options)
+        let Some(archive) = &options.archive else {
+            return Ok(());
+        };
+
+        let skipped = report
+            .with_status(TrackStatus::Skipped)
+            .filter_map(|track| track.path.clone());
+        for path in existing.into_iter().chain(skipped) {
+            if let Some(content) = self.storage.read(&path).await? {
+                self.archive(&path, &content, options).await?;
+            }
+        }
+        self.archive_covers(archive, planned, report, options).await?;
+        archive.finish().await
+    }
+
+    /// Adds the cover of every archived track: as `cover.jpg` to folders holding the tracks
+    /// of a single album, named like the track next to it otherwise. Covers which can't be
+    /// fetched are left out.
+    async fn archive_covers(
+        &self,
+        archive: &Archive,
+        planned: &[PlannedTrack],
+        report: &JobReport,
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        let mut folders: BTreeMap<PathBuf, Vec<(PathBuf, FileId)>> = BTreeMap::new();
+        for planned in planned {
+            let Some(cover) = planned.metadata.album.cover else {
+                continue;
+            };
+            let uri = planned.track.id.to_uri().unwrap_or_default();
+            let path = report
+                .tracks
+                .iter()
+                .find(|track| track.track == uri)
+                .and_then(|track| track.path.clone())
+                .unwrap_or_else(|| planned.path.clone());
+            if !archive.contains(&options.destination, &path).await {
+                continue;
+            }
+
+            let folder = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            folders.entry(folder).or_default().push((path, cover));
+        }
+
+        let mut images: HashMap<FileId, Option<Vec<u8>>> = HashMap::new();
+        for (folder, files) in folders {
+            let covers = if files.iter().all(|(_, cover)| *cover == files[0].1) {
+                vec![(folder.join(COVER_FILE_NAME), files[0].1)]
+            } else {
+                files
+                    .into_iter()
+                    .map(|(path, cover)| (path.with_extension("jpg"), cover))
+                    .collect()
+            };
+
+            for (path, cover) in covers {
+                let image = match images.entry(cover) {
+                    hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    hash_map::Entry::Vacant(entry) => match self.fetch_cover(cover).await {
+                        Ok(image) => entry.insert(Some(image)),
+                        Err(e) => {
+                            tracing::warn!("Leaving the cover {:?} out of the archive: {}", path, e);
+                            entry.insert(None)
+                        }
+                    },
+                };
+                if let Some(image) = image {
+                    self.archive(&path, image, options).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_cover(&self, cover: FileId) -> Result<Vec<u8>> {
+        let image = librespot::metadata::cover::get(self.session, cover).try_fold(Vec::new(), |mut image, chunk| async move {
+            image.extend_from_slice(&chunk);
+            Ok(image)
+        });
+
+        tokio::time::timeout(COVER_TIMEOUT, image)
+            .await
+            .map_err(|_| anyhow::anyhow!("Fetching the cover timed out"))?
+            .map_err(|_| anyhow::anyhow!("Failed to fetch the cover"))
+    }
+
+    /// Finishes the job's archive once the job ended, also when it ended early or failed, so
+    /// its writer is never left with an unterminated archive.
+    pub(crate) async fn close_archive<T>(&self, result: Result<T>, options: &DownloadOptions) -> Result<T> {
+        let Some(archive) = &options.archive else {
+            return result;
+        };
+
+        // Does nothing if the job finished it already
+        match (archive.finish().await, result) {
+            (Err(e), Ok(_)) => Err(e),
+            (Err(e), Err(job)) => {
+                tracing::warn!("Failed to finish the archive: {}", e);
+                Err(job)
+            }
+            (Ok(()), result) => result,
+        }
+    }
+
+    /// Adds a written file to the job's archive, if it has one.
+    async fn archive(&self, path: &Path, content: &[u8], options: &DownloadOptions) -> Result<()> {
+        match &options.archive {
+            Some(archive) => archive.add(&options.destination, path, content).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Downloads tracks whose output paths were already resolved by `plan_tracks`.
+    pub async fn download_planned(
+        &self,
+        planned: Vec<PlannedTrack>,
+        options: &DownloadOptions,
+    ) -> Result<JobReport> {
+        if options.archive.is_some() && options.state_file.is_some() {
+            return Err(anyhow::anyhow!(
+                "A job streaming into an archive can't be resumed, leave out either the archive or the state file"
+            ));
+        }
+        self.check_space(&planned, options)?;
+
+        let checkpoint = match &options.state_file {
+            Some(path) => {
+                let tracks: Vec<Track> = planned.iter().map(|planned| planned.track.clone()).collect();
+                Some(Checkpoint::create(path, options, &tracks).await?)
+            }
+            None => None,
+        };
+
+        self.run(planned, options, checkpoint).await
+    }
+
+    /// Continues the job saved in the checkpoint with the tracks which weren't downloaded yet.
+    pub async fn resume(&self, checkpoint: Checkpoint, job: JobHandle) -> Result<JobReport> {
+        let mut options = checkpoint.options();
+        options.job = job;
+
+        // The whole job is planned again so collisions resolve the same way as before
+        let planned = self.plan_tracks(checkpoint.tracks(), &options).await?;
+        let unfinished = planned
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !checkpoint.is_finished(*index))
+            .map(|(_, planned)| planned.clone())
+            .collect::<Vec<_>>();
+        self.check_space(&unfinished, &options)?;
+
+        let report = self.run(unfinished, &options, Some(checkpoint)).await?;
+        self.write_playlist_files(&planned, &options).await?;
+        self.run_job_hooks(&report, &options).await?;
+
+        Ok(report)
+    }
+
+    /// Estimates the size of the files the planned tracks will be written to and compares it
+    /// with the free space at the destination, which is only known on the local file system.
+    pub fn estimate_space(&self, planned: &[PlannedTrack], options: &DownloadOptions) -> Result<SpaceEstimate> {
+        let written: Vec<&PlannedTrack> = planned.iter().filter(|planned| !planned.is_skipped()).collect();
+
+        Ok(SpaceEstimate {
+            tracks: written.len(),
+            estimated_bytes: written
+                .iter()
+                .flat_map(|planned| {
+                    options
+                        .encodings()
+                        .map(|(format, settings)| space::estimate_track_size(&planned.metadata, format, settings, options.quality))
+                })
+                .sum(),
+            available_bytes: if self.storage.is_local() {
+                Some(space::available_space(&options.destination)?)
+            } else {
+                None
+            },
+            reserve_bytes: options.space_check.reserve_bytes,
+        })
+    }
+
+    /// Refuses to start or warns, depending on the options, when the job won't fit on the disk.
+    fn check_space(&self, planned: &[PlannedTrack], options: &DownloadOptions) -> Result<()> {
+        // The free space of remote storages isn't known
+        if options.space_check.action == SpaceAction::Ignore || !self.storage.is_local() {
+            return Ok(());
+        }
+
+        let estimate = self.estimate_space(planned, options)?;
+        if estimate.fits() {
+            tracing::info!("Space check: {}", estimate);
+            return Ok(());
+        }
+
+        match options.space_check.action {
+            SpaceAction::Refuse => Err(anyhow::anyhow!("Not enough free space: {}", estimate)),
+            _ => {
+                tracing::warn!("Probably not enough free space: {}", estimate);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes or updates the playlist files of every playlist and album in the job.
+    pub async fn write_playlist_files(&self, planned: &[PlannedTrack], options: &DownloadOptions) -> Result<()> {
+        let mut collections: Vec<(&Track, Vec<&PlannedTrack>)> = Vec::new();
+        for track in planned.iter().filter(|planned| planned.track.playlist_id.is_some() || planned.track.album_id.is_some()) {
+            let same_collection = |other: &Track| {
+                other.playlist_id == track.track.playlist_id && other.album_id == track.track.album_id
+            };
+            match collections.iter_mut().find(|(first, _)| same_collection(first)) {
+                Some((_, tracks)) => tracks.push(track),
+                None => collections.push((&track.track, vec![track])),
+            }
+        }
+
+        for (first, tracks) in collections {
+            let name = match first.playlist_id {
+                Some(playlist_id) => {
+                    librespot::metadata::Playlist::get(self.session, playlist_id)
+                        .await
+                        .map_err(|_| anyhow::anyhow!("Failed to get playlist"))?
+                        .name
+                }
+                None => tracks[0].metadata.album.name.clone(),
+            };
+
+            let files = playlist_file::write(
+                &name,
+                &tracks,
+                &options.destination,
+                options.playlist_files,
+                &options.sanitizer,
+                self.storage.as_ref(),
+            )
+            .await?;
+            for (path, content) in files {
+                self.archive(&path, content.as_bytes(), options).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the metadata and renders the output path of every track, then resolves
+    /// the paths claimed by more than one track before anything is written.
+    pub async fn plan_tracks(&self, tracks: Vec<Track>, options: &DownloadOptions) -> Result<Vec<PlannedTrack>> {
+        let mut planned = futures::stream::iter(tracks)
+            .map(|track| self.plan_track(track, options))
+            .buffered(options.parallel)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        plan::resolve_collisions(
+            &mut planned,
+            options.collisions,
+            options.sanitizer.profile,
+            &*self.manifest.lock().await,
+        )?;
+
+        Ok(planned)
+    }
+
+    async fn plan_track(&self, track: Track, options: &DownloadOptions) -> Result<PlannedTrack> {
+        let metadata = track.metadata(self.session).await?;
+        let path = PathBuf::from(self.path_for(&track, &metadata, options).await?);
+        let extra_paths = self.extra_paths(&track, &metadata, &path, options).await?;
+        Ok(PlannedTrack::new(track, metadata, path).with_extra_paths(extra_paths))
+    }
+
+    /// Resolves everything a job would do, down to the source quality of every track, without
+    /// fetching any audio. Unlike `plan_tracks`, tracks which can't be resolved end up in the
+    /// plan as unavailable instead of failing it.
+    pub async fn dry_run(&self, tracks: Vec<Track>, options: &DownloadOptions) -> Result<JobPlan> {
+        let results = futures::stream::iter(tracks)
+            .map(|track| async move {
+                let result = self.plan_track(track.clone(), options).await;
+                (track, result)
+            })
+            .buffered(options.parallel)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut unavailable = Vec::new();
+        let mut planned = Vec::new();
+        for (index, (track, result)) in results.into_iter().enumerate() {
+            match result {
+                Ok(track) => planned.push(track),
+                Err(e) => unavailable.push((index, PlanEntry::unavailable(&track, &e))),
+            }
+        }
+
+        plan::resolve_collisions(
+            &mut planned,
+            options.collisions,
+            options.sanitizer.profile,
+            &*self.manifest.lock().await,
+        )?;
+
+        let job = &planned;
+        let mut entries = futures::stream::iter(0..job.len())
+            .map(|index| async move {
+                let planned = &job[index];
+                let track = &planned.track;
+                let source_quality = match quality::resolve(self.session, track.id, options.quality).await {
+                    Ok(quality) => quality,
+                    Err(e) => return Ok(PlanEntry::unavailable(track, &e)),
+                };
+
+                let album = options.single_file_albums && track.album_id.is_some() && track.playlist_id.is_none();
+                let path = if album {
+                    self.album_path(planned, options)?
+                } else {
+                    planned.path.clone()
+                };
+                let exists = self.storage.exists(&path).await?;
+
+                // Skipped like in the real run, where an album is skipped as a whole
+                let existing = if planned.is_skipped() {
+                    None
+                } else if album {
+                    let tracks: Vec<&Track> = job
+                        .iter()
+                        .filter(|other| other.track.album_id == track.album_id && other.track.playlist_id.is_none())
+                        .filter(|other| !other.is_skipped())
+                        .map(|other| &other.track)
+                        .collect();
+                    self.existing(&tracks, &path, options).await?
+                } else {
+                    self.existing(&[track], &path, options).await?
+                };
+                let action = if planned.is_skipped() || existing.is_some() {
+                    PlanAction::Skip
+                } else {
+                    PlanAction::Download
+                };
+
+                Ok::<_, anyhow::Error>(PlanEntry {
+                    track: track.id.to_uri().unwrap_or_default(),
+                    action,
+                    title: Some(planned.metadata.track_name.clone()),
+                    exists,
+                    path: Some(existing.unwrap_or(path)),
+                    extra_paths: planned.extra_paths.clone(),
+                    estimated_bytes: if action == PlanAction::Download {
+                        options
+                            .encodings()
+                            .map(|(format, settings)| space::estimate_track_size(&planned.metadata, format, settings, source_quality))
+                            .sum()
+                    } else {
+                        0
+                    },
+                    source_quality: Some(source_quality),
+                    collision: planned.collision.clone(),
+                    error: None,
+                })
             })
-            .buffer_unordered(options.parallel)
+            .buffered(options.parallel)
             .try_collect::<Vec<_>>()
             .await?;
 
+        let downloaded: Vec<PlannedTrack> = planned
+            .iter()
+            .zip(&entries)
+            .filter(|(_, entry)| entry.action == PlanAction::Download)
+            .map(|(planned, _)| planned.clone())
+            .collect();
+        let space = self.estimate_space(&downloaded, options)?;
+
+        // Put the unavailable tracks back where they were in the job
+        for (index, entry) in unavailable {
+            entries.insert(index.min(entries.len()), entry);
+        }
+
+        Ok(JobPlan { tracks: entries, space })
+    }
+
+    async fn run(
+        &self,
+        planned: Vec<PlannedTrack>,
+        options: &DownloadOptions,
+        checkpoint: Option<Checkpoint>,
+    ) -> Result<JobReport> {
+        options.check_formats()?;
+        let queued: Vec<Track> = planned.iter().map(|planned| planned.track.clone()).collect();
+        let work = self.group_albums(planned, options)?;
+
+        let state_forwarder = self.forward_job_state(&options.job).await;
+        let checkpoint = checkpoint.map(Mutex::new);
+        let checkpoint = &checkpoint;
+        if let Some(queue) = &self.queue {
+            queue.set_tracks(&queued);
+        }
+
+        let memory = options.memory_budget.as_ref().map(MemoryGate::new);
+        let memory = &memory;
+        let parallel = options.memory_budget.map_or(options.parallel, |budget| budget.max_parallel);
+
+        let tracks = futures::stream::iter(work)
+            .map(|work| async move {
+                let reservation = match memory {
+                    Some(memory) => Some(memory.reserve(work.memory(options)).await),
+                    None => None,
+                };
+                let permit = self.wait_for_slot(&work, options).await;
+                let metadata: Vec<(String, HookMetadata)> = if options.hooks.is_empty() {
+                    Vec::new()
+                } else {
+                    work.planned()
+                        .into_iter()
+                        .map(|planned| (planned.track.id.to_uri().unwrap_or_default(), HookMetadata::from(&planned.metadata)))
+                        .collect()
+                };
+                let (tracks, reports) = match work {
+                    Work::Track(planned) => {
+                        let track = planned.track.clone();
+                        let collision = planned.collision.clone();
+                        let report = self.download_track(*planned, options).await?.with_collision(collision);
+                        (vec![track], vec![report])
+                    }
+                    Work::Album(planned) => {
+                        let tracks: Vec<Track> = planned.iter().map(|planned| planned.track.clone()).collect();
+                        (tracks, self.download_album(planned, options).await?)
+                    }
+                };
+                if let Some(queue) = &self.queue {
+                    for (track, report) in tracks.iter().zip(&reports) {
+                        queue.set_track_state(track, QueuedTrackState::from(&report.status));
+                    }
+                }
+                if let Some(checkpoint) = checkpoint {
+                    let mut checkpoint = checkpoint.lock().await;
+                    for (track, report) in tracks.iter().zip(&reports) {
+                        checkpoint.update(track, report).await?;
+                    }
+                }
+                // Hooks can take a while, the next unit gets the slot and the memory meanwhile
+                drop(permit);
+                drop(reservation);
+                self.run_track_hooks(&metadata, &reports, options).await?;
+                Ok::<_, anyhow::Error>(reports)
+            })
+            .buffer_unordered(parallel.max(1))
+            .try_concat()
+            .await;
+
+        state_forwarder.abort();
+        let tracks = tracks?;
+
+        if let Some(checkpoint) = checkpoint {
+            checkpoint.lock().await.finish().await?;
+        }
+
+        Ok(JobReport {
+            tracks,
+            ..JobReport::default()
+        })
+    }
+
+    /// Runs the track hooks for every report of a work unit, `metadata` holds the uri and
+    /// metadata of its tracks.
+    async fn run_track_hooks(
+        &self,
+        metadata: &[(String, HookMetadata)],
+        reports: &[TrackReport],
+        options: &DownloadOptions,
+    ) -> Result<()> {
+        for report in reports {
+            let Some((_, metadata)) = metadata.iter().find(|(uri, _)| *uri == report.track) else {
+                continue;
+            };
+            options
+                .hooks
+                .run(HookPayload::Track {
+                    report: report.clone(),
+                    metadata: Box::new(metadata.clone()),
+                })
+                .await?;
+        }
         Ok(())
     }
 
-    #[tracing::instrument(name = "download_track", skip(self))]
-    async fn download_track(&self, track: Track, options: &DownloadOptions) -> Result<()> {
-        let metadata = track.metadata(&self.session).await?;
-        tracing::info!("Downloading track: {:?}", metadata);
+    /// Runs the job hooks once the job, including its playlist files, is done.
+    pub(crate) async fn run_job_hooks(&self, report: &JobReport, options: &DownloadOptions) -> Result<()> {
+        if options.hooks.is_empty() {
+            return Ok(());
+        }
+        options.hooks.run(HookPayload::Job { report: report.clone() }).await
+    }
 
-        let file_name = self.get_file_name(&track).await;
-        let file_name_clone = file_name.clone();
+    /// Waits until the queue has a free slot for the work unit, if the job is queued. Paused
+    /// jobs don't take slots, cancelled ones go ahead without one to report their tracks.
+    async fn wait_for_slot(&self, work: &Work, options: &DownloadOptions) -> Option<Permit> {
+        let queue = self.queue.as_ref()?;
+        let tracks: Vec<&Track> = work.planned().into_iter().map(|planned| &planned.track).collect();
+        if tracks.is_empty() {
+            return None;
+        }
 
-        
-        let mut path = String::new();
+        let cancel = options.job.track_token(tracks[0].id);
+        options.job.wait_while_paused(&cancel).await;
+        let permit = tokio::select! {
+            permit = queue.acquire() => permit,
+            _ = options.job.cancelled() => return None,
+        };
 
+        for track in tracks {
+            queue.set_track_state(track, QueuedTrackState::Running);
+        }
+        Some(permit)
+    }
 
-        if let Some(playlist) = track.playlist_id {
+    /// Splits the job into the units which are downloaded in parallel, putting the tracks of
+    /// each album together when albums are written as single files. Albums which can't be
+    /// written as a single file are refused before anything is fetched.
+    fn group_albums(&self, planned: Vec<PlannedTrack>, options: &DownloadOptions) -> Result<Vec<Work>> {
+        let mut work: Vec<Work> = Vec::new();
+        for planned in planned {
+            let album_id = planned.track.album_id.filter(|_| options.single_file_albums && planned.track.playlist_id.is_none());
+            let Some(album_id) = album_id else {
+                work.push(Work::Track(Box::new(planned)));
+                continue;
+            };
+
+            let album = work.iter_mut().find_map(|work| match work {
+                Work::Album(tracks) if tracks[0].track.album_id == Some(album_id) => Some(tracks),
+                _ => None,
+            });
+            match album {
+                Some(tracks) => tracks.push(planned),
+                None => work.push(Work::Album(vec![planned])),
+            }
+        }
 
-            let playlist_name = self.playlist_name(playlist).await?;
+        for work in work.iter_mut() {
+            if let Work::Album(tracks) = work {
+                tracks.sort_by_key(|planned| planned.track.position);
+                let written = tracks.iter().filter(|planned| !planned.is_skipped()).count();
+                album_file::check_track_count(written)?;
+            }
+        }
 
-            path.push_str(options
-                .destination
-                .join(playlist_name)
-                .join(file_name_clone.clone())
-                .with_extension(options.format.extension())
-                .to_str()
-                .ok_or(anyhow::anyhow!("Could not set the output path"))?
-                .to_string().as_str());
-        } else if let Some(album) = track.album_id {
-            let album_name = self.album_name(album).await?;
-
-            path.push_str(options
-                .destination
-                .join(album_name)
-                .join(file_name_clone.clone())
-                .with_extension(options.format.extension())
-                .to_str()
-                .ok_or(anyhow::anyhow!("Could not set the output path"))?
-                .to_string().as_str());
+        Ok(work)
+    }
+
+    /// Sends every change of the job's state as a progress event.
+    async fn forward_job_state(&self, job: &JobHandle) -> tokio::task::JoinHandle<()> {
+        let sender = self.state.lock().await.sender.clone();
+        let mut state = job.subscribe();
+
+        tokio::spawn(async move {
+            while state.changed().await.is_ok() {
+                let msg = Action::Job { state: *state.borrow_and_update() };
+                let as_json_str = serde_json::to_string(&msg).unwrap();
+
+                if let Err(e) = sender.send(crate::DownloadStateOpts::MessageSender(as_json_str)) {
+                    tracing::error!("Error sending message via websocket: {:?}", e);
+                }
+            }
+        })
+    }
+
+    #[tracing::instrument(name = "download_track", skip(self, planned), fields(track = ?planned.track.id))]
+    async fn download_track(&self, planned: PlannedTrack, options: &DownloadOptions) -> Result<TrackReport> {
+        if planned.is_skipped() {
+            tracing::info!("Skipping track with a taken output path: {:?}", planned.path);
+            return Ok(TrackReport::new(&planned.track, None, TrackStatus::Skipped));
+        }
+        let PlannedTrack {
+            track,
+            metadata,
+            path,
+            extra_paths,
+            ..
+        } = planned;
+        if let Some(existing) = self.existing(&[&track], &path, options).await? {
+            tracing::info!("Skipping track which exists already: {:?}", existing);
+            return Ok(TrackReport::new(&track, Some(existing), TrackStatus::Skipped));
+        }
+
+        let cancel = options.job.track_token(track.id);
+        options.job.wait_while_paused(&cancel).await;
+        if cancel.is_cancelled() {
+            tracing::info!("Skipping cancelled track: {:?}", track.id);
+            return Ok(TrackReport::new(&track, None, TrackStatus::Cancelled));
+        }
+
+        tracing::info!("Downloading track: {:?}", metadata);
+
+        let path = path
+            .to_str()
+            .ok_or(anyhow::anyhow!("Could not set the output path"))?
+            .to_string();
+        let file_name = Path::new(&path)
+            .file_stem()
+            .ok_or(anyhow::anyhow!("Could not set the output path"))?
+            .to_string_lossy()
+            .into_owned();
+
+        let progress = self.start_progress(&file_name, options).await?;
+
+        let streams = if options.format == Format::Ogg {
+            match self.fetch_original(&track, &metadata, &cancel, &progress, options).await? {
+                Outcome::Done((stream, quality, issues)) => Outcome::Done((vec![stream], quality, issues)),
+                Outcome::Cancelled => Outcome::Cancelled,
+                Outcome::Failed(reason) => Outcome::Failed(reason),
+            }
         } else {
-            path.push_str(
-                options
-                .destination
-                .join(file_name_clone.clone())
-                .with_extension(options.format.extension())
-                .to_str()
-                .ok_or(anyhow::anyhow!("Could not set the output path"))?
-                .to_string().as_str()
-            );
+            self.capture_and_encode(&track, &metadata, &cancel, &progress, options).await?
+        };
+        let (streams, quality, issues) = match streams {
+            Outcome::Done(streams) => streams,
+            Outcome::Cancelled => {
+                return self.cancelled(&[&track], None, &progress).await.map(|mut reports| reports.remove(0));
+            }
+            Outcome::Failed(reason) => return Ok(self.failed(&[&track], reason, &progress).await.remove(0)),
+        };
+
+        progress.set(format!("Writing {}", &file_name), Action::Writing { file_name: file_name.clone() }).await;
+        let paths = std::iter::once(PathBuf::from(&path)).chain(extra_paths.iter().cloned());
+        for ((output_path, (format, settings)), stream) in paths.zip(options.encodings()).zip(streams) {
+            let stream = self.tag_stream(&metadata, quality, format, stream)?;
+
+            tracing::info!("Writing track: {:?} to file: {:?}", file_name, &output_path);
+            tokio::select! {
+                result = self.storage.write(&output_path, &stream.stream) => result?,
+                _ = cancel.cancelled() => {
+                    let partial_file = EncodedStream::partial_path(&output_path);
+                    return self.cancelled(&[&track], Some(&partial_file), &progress).await.map(|mut reports| reports.remove(0));
+                }
+            }
+
+            self.record_in_manifest(&track, &output_path, format, settings, quality, &stream.stream).await?;
+            self.archive(&output_path, &stream.stream, options).await?;
         }
 
-        let (sink, mut sink_channel) = ChannelSink::new(metadata.clone());
+        progress.finish().await;
+        Ok(TrackReport::new(&track, Some(PathBuf::from(path)), TrackStatus::Downloaded)
+            .with_issues(issues)
+            .with_extra_paths(extra_paths))
+    }
 
-        let file_size = sink.get_approximate_size();
+    /// The paths the extra formats of a track are written to: next to the main file at `path`,
+    /// or rendered from the format's own templates.
+    async fn extra_paths(
+        &self,
+        track: &Track,
+        metadata: &TrackMetadata,
+        path: &Path,
+        options: &DownloadOptions,
+    ) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::with_capacity(options.extra_formats.len());
+        for output in &options.extra_formats {
+            let path = match &output.templates {
+                Some(templates) => PathBuf::from(self.render_path(track, metadata, templates, output.format, options).await?),
+                // The file name ends with the main format's extension, nothing else gets cut off
+                None => path.with_extension(output.format.extension()),
+            };
+            paths.push(path);
+        }
+        Ok(paths)
+    }
 
-        let (mut player, _) = Player::new(
-            self.player_config.clone(),
-            self.session.clone(),
-            self.volume_getter(),
-            move || Box::new(sink),
-        );
+    /// Downloads the tracks of an album in order and writes them as one FLAC file with an
+    /// embedded cue sheet, plus an external `.cue` file next to it.
+    #[tracing::instrument(name = "download_album", skip(self, planned), fields(album = ?planned.first().and_then(|planned| planned.track.album_id)))]
+    async fn download_album(&self, planned: Vec<PlannedTrack>, options: &DownloadOptions) -> Result<Vec<TrackReport>> {
+        if options.format != Format::Flac {
+            return Err(anyhow::anyhow!("Single file albums can only be written as flac"));
+        }
+
+        let (skipped, planned): (Vec<PlannedTrack>, Vec<PlannedTrack>) =
+            planned.into_iter().partition(PlannedTrack::is_skipped);
+        let mut reports: Vec<TrackReport> = skipped
+            .iter()
+            .map(|planned| TrackReport::new(&planned.track, None, TrackStatus::Skipped))
+            .collect();
+        let Some(first) = planned.first() else {
+            return Ok(reports);
+        };
+        let tracks: Vec<&Track> = planned.iter().map(|planned| &planned.track).collect();
+
+        let path = self.album_path(first, options)?;
+        if let Some(existing) = self.existing(&tracks, &path, options).await? {
+            tracing::info!("Skipping album which exists already: {:?}", existing);
+            reports.extend(
+                tracks
+                    .iter()
+                    .map(|track| TrackReport::new(track, Some(existing.clone()), TrackStatus::Skipped)),
+            );
+            return Ok(reports);
+        }
+        let path = path
+            .to_str()
+            .ok_or(anyhow::anyhow!("Could not set the output path"))?
+            .to_string();
+        let file_name = Path::new(&path)
+            .file_stem()
+            .ok_or(anyhow::anyhow!("Could not set the output path"))?
+            .to_string_lossy()
+            .into_owned();
+
+        let progress = self.start_progress(&file_name, options).await?;
+
+        let mut samples = Vec::new();
+        let mut album_tracks = Vec::with_capacity(planned.len());
+        let mut track_issues = Vec::with_capacity(planned.len());
+        for planned in &planned {
+            let cancel = options.job.track_token(planned.track.id);
+            options.job.wait_while_paused(&cancel).await;
+            if cancel.is_cancelled() {
+                reports.extend(self.cancelled(&tracks, None, &progress).await?);
+                return Ok(reports);
+            }
+
+            tracing::info!("Downloading album track: {:?}", planned.metadata);
+            let (mut track_samples, quality, issues) = match self.capture(&planned.track, &planned.metadata, &cancel, &progress, options).await? {
+                Outcome::Done(captured) => captured,
+                Outcome::Cancelled => {
+                    reports.extend(self.cancelled(&tracks, None, &progress).await?);
+                    return Ok(reports);
+                }
+                // The album file can't be written without every track
+                Outcome::Failed(reason) => {
+                    reports.extend(self.failed(&tracks, reason, &progress).await);
+                    return Ok(reports);
+                }
+            };
+
+            album_tracks.push(AlbumTrack {
+                metadata: &planned.metadata,
+                offset: (samples.len() / 2) as u64,
+                quality,
+            });
+            track_issues.push(issues);
+            samples.append(&mut track_samples);
+        }
+        let total_samples = (samples.len() / 2) as u64;
+
+        progress.set(format!("Encoding {}", &file_name), Action::Encoding { file_name: file_name.clone() }).await;
+        let encoder = crate::encoder::get_encoder(options.format).ok_or(anyhow::anyhow!("Flac is always encoded"))?;
+        let settings = EncoderSettings::new(options.format, options.compression);
+        let stream = encoder.encode(Samples::new(samples, 44100, 2, 16), settings).await?;
+
+        let audio_file_name = Path::new(&path)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let stream = album_file::tag_stream(&album_tracks, total_samples, &audio_file_name, stream)?;
+        let cue_sheet = album_file::cue_sheet(&album_tracks, &audio_file_name);
+
+        progress.set(format!("Writing {}", &file_name), Action::Writing { file_name: file_name.clone() }).await;
+        tracing::info!("Writing album to file: {}", &path);
+        let cue_path = Path::new(&path).with_extension("cue");
+        self.storage.write(Path::new(&path), &stream.stream).await?;
+        self.storage.write(&cue_path, cue_sheet.as_bytes()).await?;
+        self.archive(Path::new(&path), &stream.stream, options).await?;
+        self.archive(&cue_path, cue_sheet.as_bytes(), options).await?;
+
+        for ((track, album_track), issues) in tracks.iter().zip(&album_tracks).zip(track_issues) {
+            self.record_in_manifest(
+                track,
+                Path::new(&path),
+                options.format,
+                settings,
+                album_track.quality,
+                &stream.stream,
+            )
+            .await?;
+            reports.push(TrackReport::new(track, Some(PathBuf::from(&path)), TrackStatus::Downloaded).with_issues(issues));
+        }
+
+        progress.finish().await;
+        Ok(reports)
+    }
 
-        let pb = self.progress_bar.add(ProgressBar::new(file_size as u64));
+    /// Sets up the progress bar and starts sending the progress events of a download.
+    async fn start_progress(&self, file_name: &str, options: &DownloadOptions) -> Result<Progress> {
+        let pb = self.progress_bar.add(ProgressBar::new(0));
         pb.enable_steady_tick(Duration::from_millis(100));
         pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
             .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
             .progress_chars("#>-"));
+        pb.set_message(file_name.to_string());
 
-        let filename_clone_of_clone = file_name.clone();
         let message = Arc::new(Mutex::new(Action::Downloading {
-            file_name: file_name.clone(),
+            file_name: file_name.to_string(),
             downloaded_bytes: 0,
             total_bytes: 0
         }));
-        drop(filename_clone_of_clone);
 
-       
         let stop_flag = Arc::new(Mutex::new(false));
 
         let message_clone = Arc::clone(&message);
         let stop_flag_clone = Arc::clone(&stop_flag);
 
         let sender = self.state.lock().await.sender.clone();
+        let job = options.job.clone();
+        let paused_file_name = file_name.to_string();
 
         tokio::spawn(async move {
             loop {
                 let msg: Action;
                 {
                     let guard = message_clone.lock().await;
-                    msg = guard.clone();
+                    msg = match *guard {
+                        Action::Downloading { .. } if job.is_paused() => Action::Paused {
+                            file_name: paused_file_name.clone()
+                        },
+                        _ => guard.clone()
+                    };
                 }
 
                 let as_json_str = serde_json::to_string(&msg).unwrap();
@@ -214,27 +1271,320 @@ impl<'a> Downloader<'a> {
             }
         });
 
-        pb.set_message(file_name.clone());
+        Ok(Progress {
+            pb,
+            message,
+            stop_flag,
+            file_name: file_name.to_string(),
+        })
+    }
+
+    /// Plays the track through the player and encodes its samples.
+    async fn capture_and_encode(
+        &self,
+        track: &Track,
+        metadata: &TrackMetadata,
+        cancel: &CancellationToken,
+        progress: &Progress,
+        options: &DownloadOptions,
+    ) -> Result<Outcome<(Vec<EncodedStream>, SourceQuality, Vec<AudioIssue>)>> {
+        let (mut samples, quality, issues) = match self.capture(track, metadata, cancel, progress, options).await? {
+            Outcome::Done(captured) => captured,
+            Outcome::Cancelled => return Ok(Outcome::Cancelled),
+            Outcome::Failed(reason) => return Ok(Outcome::Failed(reason)),
+        };
+
+        let file_name = progress.file_name.clone();
+        progress.set(format!("Encoding {}", &file_name), Action::Encoding { file_name }).await;
+
+        let encodings: Vec<(Format, EncoderSettings)> = options.encodings().collect();
+        let count = encodings.len();
+
+        // Every format is encoded from the same samples, the last one takes them over
+        let encode = async {
+            let mut streams = Vec::with_capacity(count);
+            for (index, (format, settings)) in encodings.into_iter().enumerate() {
+                let encoder = crate::encoder::get_encoder(format)
+                    .ok_or(anyhow::anyhow!("{} can't be encoded", format.extension()))?;
+                let samples = if index + 1 == count {
+                    std::mem::take(&mut samples)
+                } else {
+                    samples.clone()
+                };
+                streams.push(encoder.encode(Samples::new(samples, 44100, 2, 16), settings).await?);
+            }
+            Ok::<_, anyhow::Error>(streams)
+        };
+        tokio::select! {
+            streams = encode => Ok(Outcome::Done((streams?, quality, issues))),
+            _ = cancel.cancelled() => Ok(Outcome::Cancelled),
+        }
+    }
+
+    /// Fetches and decrypts the original Ogg Vorbis file of the track, skipping the player and
+    /// the encoder.
+    async fn fetch_original(
+        &self,
+        track: &Track,
+        metadata: &TrackMetadata,
+        cancel: &CancellationToken,
+        progress: &Progress,
+        options: &DownloadOptions,
+    ) -> Result<Outcome<(EncodedStream, SourceQuality, Vec<AudioIssue>)>> {
+        let mut stalls = 0;
+        let mut checks = 0;
+        loop {
+            // Retries count as starts as well
+            if !self.pacer.wait_for_start(&options.pacing, cancel).await {
+                return Ok(Outcome::Cancelled);
+            }
+
+            match self.read_original(track, metadata, cancel, progress, options).await? {
+                Attempt::Done((stream, quality)) => {
+                    let issues = options.audio_check.check_ogg(&stream.stream, metadata)?;
+                    if options.audio_check.should_retry(&issues, checks) {
+                        checks += 1;
+                        tracing::warn!("{:?} looks broken, downloading it again: {:?}", metadata.track_name, issues);
+                        continue;
+                    }
+                    return Ok(Outcome::Done((stream, quality, self.suspect(metadata, issues))));
+                }
+                Attempt::Cancelled => return Ok(Outcome::Cancelled),
+                Attempt::Stalled(stall) => {
+                    if let Some(reason) = self.stalled(metadata, stall, &mut stalls, options) {
+                        return Ok(Outcome::Failed(reason));
+                    }
+                }
+            }
+        }
+    }
+
+    /// One attempt at fetching the original file, given up when the watchdog fires.
+    async fn read_original(
+        &self,
+        track: &Track,
+        metadata: &TrackMetadata,
+        cancel: &CancellationToken,
+        progress: &Progress,
+        options: &DownloadOptions,
+    ) -> Result<Attempt<(EncodedStream, SourceQuality)>> {
+        let mut timer = options.watchdog.start(metadata);
+
+        // Opening the file fetches its audio key, which is known to hang
+        let file = tokio::select! {
+            file = OriginalFile::open(self.session, track.id, options.quality) => file?,
+            stall = timer.expired() => return Ok(Attempt::Stalled(stall)),
+            _ = cancel.cancelled() => return Ok(Attempt::Cancelled),
+        };
+        timer.progress();
+
+        let quality = file.quality;
+        let throttle = self.pacer.throttle(&options.pacing, quality, cancel.clone());
+        let throttled = throttle.clone();
+        let source_bytes_per_second = f64::from(quality.kbps()) * 1000.0 / 8.0;
+
+        let pb = progress.pb.clone();
+        let message = Arc::clone(&progress.message);
+        let file_name = progress.file_name.clone();
+        let pause = options.job.pause_gate(cancel.clone());
+        // Stops the reading thread of a stalled attempt once it wakes up again
+        let stop = cancel.child_token();
+        let reading = stop.clone();
+        let read_so_far = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&read_so_far);
+
+        // Reading the file blocks until its data arrived
+        let mut reader = tokio::task::spawn_blocking(move || {
+            let mut previous = 0;
+            file.read_blocking(|read, total| {
+                pause.wait_blocking();
+                if let Some(throttle) = &throttle {
+                    throttle.wait_blocking(Duration::from_secs_f64((read - previous) as f64 / source_bytes_per_second));
+                }
+                previous = read;
+                counter.store(read, Ordering::Relaxed);
+
+                pb.set_length(total as u64);
+                pb.set_position(read as u64);
+                *message.blocking_lock() = Action::Downloading {
+                    file_name: file_name.clone(),
+                    downloaded_bytes: read,
+                    total_bytes: total,
+                };
+
+                !reading.is_cancelled()
+            })
+        });
+
+        let mut checked = 0;
+        loop {
+            tokio::select! {
+                content = &mut reader => {
+                    return Ok(match content?? {
+                        Some(content) => Attempt::Done((EncodedStream::new(content), quality)),
+                        None => Attempt::Cancelled,
+                    });
+                }
+                stall = timer.expired() => {
+                    let read = read_so_far.load(Ordering::Relaxed);
+                    let waited = throttled.as_ref().map_or(Duration::ZERO, Throttle::take_waited);
+                    if !waited.is_zero() {
+                        checked = read;
+                        timer.throttled(waited);
+                    } else if read != checked {
+                        checked = read;
+                        timer.progress();
+                    } else if options.job.is_paused() {
+                        timer.paused();
+                    } else {
+                        stop.cancel();
+                        return Ok(Attempt::Stalled(stall));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Plays the track into a channel sink and collects its samples along with the source quality
+    /// which was fetched.
+    async fn capture(
+        &self,
+        track: &Track,
+        metadata: &TrackMetadata,
+        cancel: &CancellationToken,
+        progress: &Progress,
+        options: &DownloadOptions,
+    ) -> Result<Outcome<(Vec<i32>, SourceQuality, Vec<AudioIssue>)>> {
+        let quality = quality::resolve(self.session, track.id, options.quality).await?;
+
+        let mut stalls = 0;
+        let mut checks = 0;
+        loop {
+            // Retries count as starts as well
+            if !self.pacer.wait_for_start(&options.pacing, cancel).await {
+                return Ok(Outcome::Cancelled);
+            }
+
+            match self.play(track, metadata, quality, cancel, progress, options).await? {
+                Attempt::Done(samples) => {
+                    let issues = options.audio_check.check_samples(&samples, metadata);
+                    if options.audio_check.should_retry(&issues, checks) {
+                        checks += 1;
+                        tracing::warn!("{:?} looks broken, downloading it again: {:?}", metadata.track_name, issues);
+                        continue;
+                    }
+                    return Ok(Outcome::Done((samples, quality, self.suspect(metadata, issues))));
+                }
+                Attempt::Cancelled => return Ok(Outcome::Cancelled),
+                Attempt::Stalled(stall) => {
+                    if let Some(reason) = self.stalled(metadata, stall, &mut stalls, options) {
+                        return Ok(Outcome::Failed(reason));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts a stalled attempt, returns why the track is given up on once the watchdog's
+    /// retries are used up.
+    fn stalled(&self, metadata: &TrackMetadata, stall: Stall, stalls: &mut u32, options: &DownloadOptions) -> Option<String> {
+        *stalls += 1;
+        tracing::warn!("Attempt {} at {:?} stalled: {}", stalls, metadata.track_name, stall);
+
+        if *stalls > options.watchdog.retries {
+            return Some(format!("stalled {} times, last time {}", stalls, stall));
+        }
+        None
+    }
+
+    /// Logs the issues of a track which is kept although its audio looks broken.
+    fn suspect(&self, metadata: &TrackMetadata, issues: Vec<AudioIssue>) -> Vec<AudioIssue> {
+        for issue in &issues {
+            tracing::warn!("Marking {:?} as suspect: {}", metadata.track_name, issue);
+        }
+        issues
+    }
+
+    /// One attempt at playing the track into a channel sink. The player is torn down when the
+    /// watchdog fires.
+    async fn play(
+        &self,
+        track: &Track,
+        metadata: &TrackMetadata,
+        quality: SourceQuality,
+        cancel: &CancellationToken,
+        progress: &Progress,
+        options: &DownloadOptions,
+    ) -> Result<Attempt<Vec<i32>>> {
+        let throttle = self.pacer.throttle(&options.pacing, quality, cancel.clone());
+        let (sink, mut sink_channel) = ChannelSink::new(
+            metadata.clone(),
+            options.job.pause_gate(cancel.clone()),
+            throttle.clone(),
+        );
+
+        progress.pb.set_length(sink.get_approximate_size() as u64);
+        progress.pb.set_position(0);
+
+        let player_config = PlayerConfig {
+            bitrate: options.quality.bitrate(),
+            ..self.player_config.clone()
+        };
+
+        let (mut player, _) = Player::new(
+            player_config,
+            self.session.clone(),
+            self.volume_getter(),
+            move || Box::new(sink),
+        );
 
         player.load(track.id, true, 0);
 
         let mut samples = Vec::<i32>::new();
 
+        // Cancelled along with the track, or on its own when the player stalls
+        let player_cancel = cancel.child_token();
+        let stop_player = player_cancel.clone();
         tokio::spawn(async move {
-            player.await_end_of_track().await;
+            tokio::select! {
+                _ = player.await_end_of_track() => {}
+                _ = player_cancel.cancelled() => tracing::info!("Stopping the player of a cancelled or stalled track"),
+            }
             player.stop();
         });
 
-        while let Some(event) = sink_channel.recv().await {
-    
+        let mut timer = options.watchdog.start(metadata);
+        loop {
+            let event = tokio::select! {
+                event = sink_channel.recv() => event,
+                _ = cancel.cancelled() => None,
+                stall = timer.expired() => {
+                    let waited = throttle.as_ref().map_or(Duration::ZERO, Throttle::take_waited);
+                    if !waited.is_zero() {
+                        timer.throttled(waited);
+                        continue;
+                    }
+                    if options.job.is_paused() {
+                        timer.paused();
+                        continue;
+                    }
+                    stop_player.cancel();
+                    return Ok(Attempt::Stalled(stall));
+                }
+            };
+            let Some(event) = event else {
+                break;
+            };
+
             match event {
                 SinkEvent::Write { bytes, total, mut content } => {
+                    timer.progress();
                     tracing::trace!("Written {} bytes out of {}", bytes, total);
-                    pb.set_position(bytes as u64);
+                    progress.pb.set_position(bytes as u64);
                     {
-                        let mut msg = message.lock().await;
+                        let mut msg = progress.message.lock().await;
                         *msg = Action::Downloading {
-                            file_name: file_name_clone.clone(),
+                            file_name: progress.file_name.clone(),
                             downloaded_bytes: bytes,
                             total_bytes: total
                         };
@@ -242,168 +1592,269 @@ impl<'a> Downloader<'a> {
                     samples.append(&mut content);
                 }
                 SinkEvent::Finished => {
-                    tracing::info!("Finished downloading track: {:?}", file_name);
+                    tracing::info!("Finished downloading track: {:?}", metadata.track_name);
                     break;
                 }
             }
             
         }
 
-        tracing::info!("Encoding track: {:?}", &file_name_clone);
-        pb.set_message(format!("Encoding {}", &file_name_clone));
-        {
-            let mut msg = message.lock().await;
-            *msg = Action::Encoding { file_name: file_name_clone.clone() }
-        }
-        let samples = Samples::new(samples, 44100, 2, 16);
-        let encoder = crate::encoder::get_encoder(options.format);
-        let stream = encoder.encode(samples).await?;
-
-        pb.set_message(format!("Writing {}", &file_name));
-        {
-            let mut msg = message.lock().await;
-            *msg = Action::Writing { file_name: file_name_clone.clone() }
-        }
-        tracing::info!("Writing track: {:?} to file: {}", file_name, &path);
-        stream.write_to_file(&path).await?;
-
-        self.write_metadata(&metadata, &path)?;
-
-        pb.finish_with_message(format!("Downloaded {}", &file_name));
-        {
-            let mut msg = message.lock().await;
-            let mut stop_flag = stop_flag.lock().await;
-            *msg = Action::Downloaded { file_name: file_name_clone.clone() };
-            *stop_flag = true;
+        if cancel.is_cancelled() {
+            return Ok(Attempt::Cancelled);
         }
-        Ok(())
-    }
 
-    fn volume_getter(&self) -> Box<dyn VolumeGetter + Send> {
-        Box::new(NoOpVolume)
+        Ok(Attempt::Done(samples))
     }
 
-    async fn get_file_name(&self, track: &Track) -> String {
-
-        let metadata = track.metadata(self.session).await.unwrap();
-        let base62_id = track.id.to_base62().unwrap();
-
-        // If there is more than 3 artists, add the first 3 and add "and others" at the end
-        if metadata.artists.len() > 3 {
-            let artists_name = metadata
-                .artists
-                .iter()
-                .take(3)
-                .map(|artist| artist.name.clone())
-                .collect::<Vec<String>>()
-                .join(", ");
-            return self.clean_file_name(format!(
-                "{}, and others - {} - {}",
-                artists_name, metadata.track_name, base62_id
-            ));
+    /// Wraps up tracks which were cancelled, removing the partially written file if there is one.
+    async fn cancelled(
+        &self,
+        tracks: &[&Track],
+        partial_file: Option<&Path>,
+        progress: &Progress,
+    ) -> Result<Vec<TrackReport>> {
+        tracing::info!("Cancelled: {:?}", progress.file_name);
+
+        if let Some(path) = partial_file {
+            self.storage.remove(path).await?;
         }
 
-        let artists_name = metadata
-            .artists
+        progress.pb.abandon_with_message(format!("Cancelled {}", progress.file_name));
+        progress.stop(Action::Cancelled { file_name: progress.file_name.clone() }).await;
+
+        Ok(tracks
             .iter()
-            .map(|artist| artist.name.clone())
-            .collect::<Vec<String>>()
-            .join(", ");
-        self.clean_file_name(format!("{} - {} - {}", artists_name, metadata.track_name, base62_id))
+            .map(|track| TrackReport::new(track, None, TrackStatus::Cancelled))
+            .collect())
     }
 
-    fn clean_file_name(&self, file_name: String) -> String {
-        let invalid_chars = ['<', '>', ':', '\'', '"', '/', '\\', '|', '?', '*'];
-        let mut clean = String::new();
+    /// Where the tracks, written to `path` together, exist already if `skip_existing` is set:
+    /// `path` itself, or a path every track has in the manifest in the job's format.
+    async fn existing(&self, tracks: &[&Track], path: &Path, options: &DownloadOptions) -> Result<Option<PathBuf>> {
+        if !options.skip_existing {
+            return Ok(None);
+        }
+        if self.storage.exists(path).await? {
+            return Ok(Some(path.to_path_buf()));
+        }
 
-        // Everything but Windows should allow non-ascii characters
-        let allows_non_ascii = !cfg!(windows);
-        for c in file_name.chars() {
-            if !invalid_chars.contains(&c) && (c.is_ascii() || allows_non_ascii) && !c.is_control()
-            {
-                clean.push(c);
+        let candidates = {
+            let manifest = self.manifest.lock().await;
+            let Some(first) = tracks.first() else {
+                return Ok(None);
+            };
+            let rest: Vec<Vec<PathBuf>> = tracks[1..]
+                .iter()
+                .map(|track| manifest.locate_format(&track.id, options.format))
+                .collect();
+            manifest
+                .locate_format(&first.id, options.format)
+                .into_iter()
+                .filter(|path| rest.iter().all(|paths| paths.contains(path)))
+                .collect::<Vec<_>>()
+        };
+
+        for candidate in candidates {
+            if self.storage.exists(&candidate).await? {
+                return Ok(Some(candidate));
             }
         }
-        clean
+        Ok(None)
     }
 
-    async fn playlist_name(&self, id: SpotifyId) -> Result<String> {
-        let playlist = librespot::metadata::Playlist::get(self.session, id)
-            .await.unwrap();
+    /// Wraps up tracks which were given up on, the job goes on without them.
+    async fn failed(&self, tracks: &[&Track], reason: String, progress: &Progress) -> Vec<TrackReport> {
+        tracing::error!("Failed: {:?}, {}", progress.file_name, reason);
 
-        let playlist_name = playlist.name;
-        let playlist_creator = playlist.user;
-        let base62_id = id.to_base62().unwrap();
+        progress.pb.abandon_with_message(format!("Failed {}", progress.file_name));
+        progress
+            .stop(Action::Failed {
+                file_name: progress.file_name.clone(),
+                reason: reason.clone(),
+            })
+            .await;
 
-        Ok(self.clean_file_name(format!("{} - {} - {}", playlist_creator, playlist_name, base62_id)))
-            
+        tracks
+            .iter()
+            .map(|track| TrackReport::new(track, None, TrackStatus::Failed { reason: reason.clone() }))
+            .collect()
     }
 
-    async fn album_name(&self, id: SpotifyId) -> Result<String> {
-        let album: librespot::metadata::Album = librespot::metadata::Album::get(self.session, id)
-            .await.unwrap();
+    /// Records a written file of the track, every format gets an entry of its own.
+    async fn record_in_manifest(
+        &self,
+        track: &Track,
+        path: &Path,
+        format: Format,
+        settings: EncoderSettings,
+        quality: SourceQuality,
+        content: &[u8],
+    ) -> Result<()> {
+        let source = if let Some(playlist) = track.playlist_id {
+            Some(ManifestSource::playlist(playlist)?)
+        } else if let Some(album) = track.album_id {
+            Some(ManifestSource::album(album)?)
+        } else {
+            None
+        };
 
-        let album_name: String = album.name;
-        let album_artist_vec: Vec<SpotifyId> = album.artists;
-        let base62_id = id.to_base62().unwrap();
+        let entry = ManifestEntry::new(path.to_path_buf(), format, settings, quality, content, source);
 
-        let mut artists_string: String = String::new();
+        let mut manifest = self.manifest.lock().await;
+        manifest.insert(&track.id, entry)?;
+        manifest.save().await
+    }
 
-        for (i, artist_id) in album_artist_vec.iter().enumerate() {
-            if i >= 3 {
-                artists_string.push_str("and others...");
-                break;
-            }
-            let artist_str = self.convert_artist_to_string(artist_id.clone(), self.session).await.unwrap();
-            artists_string.push_str(&format!("{}, ", artist_str));
-        }
+    /// Renders the path template matching the kind of the track.
+    async fn path_for(&self, track: &Track, metadata: &TrackMetadata, options: &DownloadOptions) -> Result<String> {
+        self.render_path(track, metadata, &options.templates, options.format, options).await
+    }
 
-        if artists_string.ends_with(", ") {
-            artists_string.truncate(artists_string.len() - 2);
-        }
+    /// Renders the template of `templates` matching the kind of the track, for a file in `format`.
+    async fn render_path(
+        &self,
+        track: &Track,
+        metadata: &TrackMetadata,
+        templates: &PathTemplates,
+        format: Format,
+        options: &DownloadOptions,
+    ) -> Result<String> {
+        let mut values = self.template_values(track, metadata)?;
+
+        let template = if track.id.audio_type == SpotifyAudioType::Podcast {
+            &templates.episode
+        } else if let Some(playlist_id) = track.playlist_id {
+            let playlist = librespot::metadata::Playlist::get(self.session, playlist_id)
+                .await
+                .map_err(|_| anyhow::anyhow!("Failed to get playlist"))?;
+
+            values.insert("collection", &playlist.name);
+            values.insert("playlist", &playlist.name);
+            values.insert("playlist_owner", &playlist.user);
+            values.insert("playlist_id", playlist_id.to_base62()?);
+            &templates.playlist
+        } else if track.album_id.is_some() {
+            values.insert("collection", &metadata.album.name);
+            &templates.album
+        } else {
+            &templates.track
+        };
+
+        let path = template::render(
+            template,
+            &values,
+            &options.destination,
+            format.extension(),
+            &options.sanitizer,
+        )?;
+
+        Ok(path
+            .to_str()
+            .ok_or(anyhow::anyhow!("Could not set the output path"))?
+            .to_string())
+    }
 
-        Ok(self.clean_file_name(format!("{} - {} - {}", artists_string, album_name, base62_id)))
-        
+    /// Renders the `album_file` template with the values of the album's first track.
+    fn album_path(&self, first: &PlannedTrack, options: &DownloadOptions) -> Result<PathBuf> {
+        let mut values = self.template_values(&first.track, &first.metadata)?;
+        values.insert("collection", &first.metadata.album.name);
+
+        template::render(
+            &options.templates.album_file,
+            &values,
+            &options.destination,
+            options.format.extension(),
+            &options.sanitizer,
+        )
     }
 
-    async fn convert_artist_to_string(&self, id: SpotifyId, session: &Session) -> Result<String> {
-        let artist = librespot::metadata::Artist::get(session, id)
-            .await.unwrap();
+    fn template_values(&self, track: &Track, metadata: &TrackMetadata) -> Result<TemplateValues> {
+        let mut values = TemplateValues::new();
+        values.insert("title", &metadata.track_name);
+        values.insert_opt("artist", metadata.artists.first().map(|artist| &artist.name));
+        values.insert("artists", self.join_artists(&metadata.artists));
+        values.insert("album", &metadata.album.name);
+        values.insert("show", &metadata.album.name);
+        values.insert_opt("album_artist", metadata.album.artists.first().map(|artist| &artist.name));
+        values.insert("album_artists", self.join_artists(&metadata.album.artists));
+        values.insert_opt("year", metadata.album.year);
+        values.insert_opt("disc", metadata.disc_number);
+        values.insert_opt("track", metadata.number);
+        values.insert("id", track.id.to_base62()?);
+        values.insert_opt("album_id", track.album_id.or(metadata.album.id).map(|id| id.to_base62()).transpose()?);
+        values.insert_opt("position", track.position);
+
+        Ok(values)
+    }
 
-        Ok(artist.name)
+    fn volume_getter(&self) -> Box<dyn VolumeGetter + Send> {
+        Box::new(NoOpVolume)
     }
 
-    fn write_metadata(&self, track: &TrackMetadata, file: &str) -> Result<()> {
+    /// Joins the artist names, cutting the list off after 3 artists.
+    fn join_artists(&self, artists: &[ArtistMetadata]) -> String {
+        let names = artists
+            .iter()
+            .take(3)
+            .map(|artist| artist.name.clone())
+            .collect::<Vec<String>>()
+            .join(", ");
 
-        let file_path = PathBuf::from(file);
-        if !file_path.exists() {
-            return Err(anyhow!("the file doesnt exist!"));
+        if artists.len() > 3 {
+            format!("{}, and others", names)
+        } else {
+            names
         }
+    }
+
+    /// Adds the track's tags to the encoded stream in memory, so the file never hits the disk untagged.
+    fn tag_stream(&self, track: &TrackMetadata, quality: SourceQuality, format: Format, stream: EncodedStream) -> Result<EncodedStream> {
 
         let artists = track.artists.clone();
 
         let album = track.album.clone();
         let track_name = track.track_name.clone();
 
-        if file_path.extension().unwrap().to_string_lossy().to_string() == "mp3" {
-            let mut tag = id3Tag::new();
+        let mut tagged = Vec::with_capacity(stream.stream.len());
 
-            tag.set_album(album.name);
-            tag.set_title(track_name);
-            tag.set_artist(self.convert_artists_to_string(artists)?);
+        match format {
+            #[cfg(feature = "mp3")]
+            Format::Mp3 => {
+                let mut tag = id3Tag::new();
 
-            tag.write_to_path(file_path, Version::Id3v24)?;
-        } else if file_path.extension().unwrap().to_string_lossy().to_string() == "flac" {
-            let mut tag = FlacTag::read_from_path(&file_path)?;
-            tag.set_vorbis("TITLE", vec![track_name]);
-            tag.set_vorbis("ALBUM", vec![album.name]);
-            tag.set_vorbis("ARTIST", vec![self.convert_artists_to_string(artists)?]);
-            tag.save()?;
-        } else {
-            return Err(anyhow!("unsupported file extension!"));
+                tag.set_album(album.name);
+                tag.set_title(track_name);
+                tag.set_artist(self.convert_artists_to_string(artists)?);
+                tag.add_frame(ExtendedText {
+                    description: SOURCE_BITRATE_TAG.to_string(),
+                    value: quality.kbps().to_string(),
+                });
+
+                tag.write_to(&mut tagged, Version::Id3v24)?;
+                tagged.extend_from_slice(&stream.stream);
+            }
+            Format::Ogg => {
+                tagged = ogg_file::set_comments(&stream.stream, &[
+                    ("TITLE", track_name),
+                    ("ALBUM", album.name),
+                    ("ARTIST", self.convert_artists_to_string(artists)?),
+                    (SOURCE_BITRATE_TAG, quality.kbps().to_string()),
+                ])?;
+            }
+            Format::Flac => {
+                let mut reader = Cursor::new(&stream.stream);
+                let mut tag = FlacTag::read_from(&mut reader)?;
+                tag.set_vorbis("TITLE", vec![track_name]);
+                tag.set_vorbis("ALBUM", vec![album.name]);
+                tag.set_vorbis("ARTIST", vec![self.convert_artists_to_string(artists)?]);
+                tag.set_vorbis(SOURCE_BITRATE_TAG, vec![quality.kbps().to_string()]);
+
+                tag.write_to(&mut tagged)?;
+                tagged.extend_from_slice(&stream.stream[reader.position() as usize..]);
+            }
         }
 
-        Ok(())
+        Ok(EncodedStream::new(tagged))
     }
 
     fn convert_artists_to_string(&self, artists: Vec<ArtistMetadata>) -> Result<String> {
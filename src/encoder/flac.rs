@@ -5,14 +5,37 @@ use flacenc::error::Verify;
 use super::execute_with_result;
 use super::EncodedStream;
 use super::Encoder;
+use super::EncoderSettings;
 use super::Samples;
 
+/// The highest compression level, the one of libFLAC's `-8` preset.
+pub const MAX_COMPRESSION: u32 = 8;
+
 #[derive(Debug)]
 pub struct FlacEncoder;
 
+/// Sets the block size, stereo decorrelation and LPC order of the libFLAC preset of a level.
+fn apply_compression(config: &mut flacenc::config::Encoder, level: u32) {
+    let (block_size, stereo, lpc_order) = match level {
+        0 => (1152, false, None),
+        1 | 2 => (1152, true, None),
+        3 => (4096, false, Some(6)),
+        4..=6 => (4096, true, Some(8)),
+        _ => (4096, true, Some(12)),
+    };
+    config.block_size = block_size;
+    config.stereo_coding.use_leftside = stereo;
+    config.stereo_coding.use_rightside = stereo;
+    config.stereo_coding.use_midside = stereo;
+    match lpc_order {
+        Some(order) => config.subframe_coding.qlpc.lpc_order = order,
+        None => config.subframe_coding.use_lpc = false,
+    }
+}
+
 #[async_trait::async_trait]
 impl Encoder for FlacEncoder {
-    async fn encode(&self, samples: Samples) -> anyhow::Result<EncodedStream> {
+    async fn encode(&self, samples: Samples, settings: EncoderSettings) -> anyhow::Result<EncodedStream> {
         let source = flacenc::source::MemSource::from_samples(
             &samples.samples,
             samples.channels as usize,
@@ -20,7 +43,17 @@ impl Encoder for FlacEncoder {
             samples.sample_rate as usize,
         );
 
-        let config = flacenc::config::Encoder::default()
+        let mut config = flacenc::config::Encoder::default();
+        if let Some(level) = settings.compression {
+            anyhow::ensure!(
+                level <= MAX_COMPRESSION,
+                "The FLAC compression level {} is above {}",
+                level,
+                MAX_COMPRESSION
+            );
+            apply_compression(&mut config, level);
+        }
+        let config = config
             .into_verified()
             .map_err(|e| anyhow::anyhow!("Failed to verify encoder config: {:?}", e))?;
 
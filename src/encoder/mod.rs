@@ -2,16 +2,20 @@ mod flac;
 #[cfg(feature = "mp3")]
 mod mp3;
 
-use std::{path::Path, str::FromStr};
+use std::{path::{Path, PathBuf}, str::FromStr};
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot::Sender;
 
 use self::{flac::FlacEncoder, mp3::Mp3Encoder};
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Format {
     Flac,
+    /// The original Ogg Vorbis stream, passed through without decoding or encoding.
+    Ogg,
     #[cfg(feature = "mp3")]
     Mp3,
 }
@@ -22,6 +26,7 @@ impl FromStr for Format {
     fn from_str(s: &str) -> Result<Self> {
         match s {
             "flac" => Ok(Format::Flac),
+            "ogg" => Ok(Format::Ogg),
             #[cfg(feature = "mp3")]
             "mp3" => Ok(Format::Mp3),
             _ => Err(anyhow::anyhow!("Unsupported format")),
@@ -34,6 +39,7 @@ impl Format {
     pub fn extension(&self) -> &'static str {
         match self {
             Format::Flac => "flac",
+            Format::Ogg => "ogg",
             #[cfg(feature = "mp3")]
             Format::Mp3 => "mp3",
         }
@@ -41,21 +47,55 @@ impl Format {
 
 }
 
+/// The settings a file was encoded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncoderSettings {
+    /// The FLAC compression level from 0 to 8, encoded like the libFLAC preset of that level.
+    /// Levels up to 2 only use the fast fixed predictors.
+    pub compression: Option<u32>,
+    /// The MP3 bitrate, rounded to the closest one LAME supports.
+    pub bitrate_kbps: Option<u32>,
+}
+
+impl EncoderSettings {
+    pub fn new(format: Format, compression: Option<u32>) -> Self {
+        match format {
+            Format::Flac => EncoderSettings {
+                compression: compression.map(|level| level.min(flac::MAX_COMPRESSION)),
+                bitrate_kbps: None,
+            },
+            Format::Ogg => EncoderSettings {
+                compression: None,
+                bitrate_kbps: None,
+            },
+            #[cfg(feature = "mp3")]
+            Format::Mp3 => EncoderSettings {
+                compression: None,
+                bitrate_kbps: Some(mp3::BITRATE_KBPS),
+            },
+        }
+    }
+}
+
+pub const PARTIAL_EXTENSION: &str = "part";
+
 const FLAC_ENCODER: &FlacEncoder = &FlacEncoder;
 #[cfg(feature = "mp3")]
 const MP3_ENCODER: &Mp3Encoder = &Mp3Encoder;
 
-pub fn get_encoder(format: Format) -> &'static dyn Encoder {
+/// The encoder of the format, `None` for `Ogg` which is passed through as it is.
+pub fn get_encoder(format: Format) -> Option<&'static dyn Encoder> {
     match format {
-        Format::Flac => FLAC_ENCODER,
+        Format::Flac => Some(FLAC_ENCODER),
+        Format::Ogg => None,
         #[cfg(feature = "mp3")]
-        Format::Mp3 => MP3_ENCODER,
+        Format::Mp3 => Some(MP3_ENCODER),
     }
 }
 
 #[async_trait::async_trait]
-pub trait Encoder {
-    async fn encode(&self, samples: Samples) -> Result<EncodedStream>;
+pub trait Encoder: Sync {
+    async fn encode(&self, samples: Samples, settings: EncoderSettings) -> Result<EncodedStream>;
 }
 
 pub struct Samples {
@@ -85,16 +125,12 @@ impl EncodedStream {
         EncodedStream { stream }
     }
 
-    pub async fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        if !path.as_ref().exists() {
-            tokio::fs::create_dir_all(
-                path.as_ref()
-                    .parent()
-                    .ok_or(anyhow::anyhow!("Could not create path"))?,
-            ).await?;
-        }
-        tokio::fs::write(path, &self.stream).await?;
-        Ok(())
+    /// The path the stream is written to before it gets renamed to `path`.
+    pub fn partial_path<P: AsRef<Path>>(path: P) -> PathBuf {
+        let mut partial_path = path.as_ref().as_os_str().to_owned();
+        partial_path.push(".");
+        partial_path.push(PARTIAL_EXTENSION);
+        PathBuf::from(partial_path)
     }
 }
 
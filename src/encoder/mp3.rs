@@ -1,5 +1,6 @@
 use anyhow::anyhow;
 use anyhow::Ok;
+use mp3lame_encoder::Birtate;
 use mp3lame_encoder::Builder;
 use mp3lame_encoder::FlushNoGap;
 use mp3lame_encoder::InterleavedPcm;
@@ -7,8 +8,39 @@ use mp3lame_encoder::InterleavedPcm;
 use super::execute_with_result;
 use super::EncodedStream;
 use super::Encoder;
+use super::EncoderSettings;
 use super::Samples;
 
+pub const BITRATE_KBPS: u32 = 160;
+
+const BITRATES: [(u32, Birtate); 16] = [
+    (8, Birtate::Kbps8),
+    (16, Birtate::Kbps16),
+    (24, Birtate::Kbps24),
+    (32, Birtate::Kbps32),
+    (40, Birtate::Kbps40),
+    (48, Birtate::Kbps48),
+    (64, Birtate::Kbps64),
+    (80, Birtate::Kbps80),
+    (96, Birtate::Kbps96),
+    (112, Birtate::Kbps112),
+    (128, Birtate::Kbps128),
+    (160, Birtate::Kbps160),
+    (192, Birtate::Kbps192),
+    (224, Birtate::Kbps224),
+    (256, Birtate::Kbps256),
+    (320, Birtate::Kbps320),
+];
+
+/// The supported bitrate closest to `kbps`.
+fn bitrate(kbps: u32) -> Birtate {
+    BITRATES
+        .iter()
+        .min_by_key(|(supported, _)| supported.abs_diff(kbps))
+        .map(|(_, bitrate)| *bitrate)
+        .unwrap_or(Birtate::Kbps160)
+}
+
 pub struct Mp3Encoder;
 
 impl Mp3Encoder {
@@ -16,6 +48,7 @@ impl Mp3Encoder {
         &self,
         sample_rate: u32,
         channels: u32,
+        bitrate_kbps: u32,
     ) -> anyhow::Result<mp3lame_encoder::Encoder> {
         let mut builder = Builder::new().ok_or(anyhow::anyhow!("Failed to create mp3 encoder"))?;
 
@@ -26,7 +59,7 @@ impl Mp3Encoder {
             anyhow::anyhow!("Failed to set number of channels for mp3 encoder: {}", e)
         })?;
         builder
-            .set_brate(mp3lame_encoder::Birtate::Kbps160)
+            .set_brate(bitrate(bitrate_kbps))
             .map_err(|e| anyhow::anyhow!("Failed to set bitrate for mp3 encoder: {}", e))?;
 
         builder
@@ -37,8 +70,9 @@ impl Mp3Encoder {
 
 #[async_trait::async_trait]
 impl Encoder for Mp3Encoder {
-    async fn encode(&self, samples: Samples) -> anyhow::Result<EncodedStream> {
-        let mut mp3_encoder = self.build_encoder(samples.sample_rate, samples.channels)?;
+    async fn encode(&self, samples: Samples, settings: EncoderSettings) -> anyhow::Result<EncodedStream> {
+        let bitrate_kbps = settings.bitrate_kbps.unwrap_or(BITRATE_KBPS);
+        let mut mp3_encoder = self.build_encoder(samples.sample_rate, samples.channels, bitrate_kbps)?;
 
         let (tx, rx) = tokio::sync::oneshot::channel();
 
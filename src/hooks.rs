@@ -0,0 +1,272 @@
+use std::fmt;
+use std::future::Future;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::report::{JobReport, TrackReport};
+use crate::track::TrackMetadata;
+
+pub type HookCallback = Arc<dyn Fn(HookPayload) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    /// After every track of a job, whatever its status.
+    Track,
+    /// After the job, once its playlist files are written.
+    Job,
+}
+
+/// What happens when a hook still fails after its retries, or times out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+    Ignore,
+    /// Logs the failure and goes on.
+    #[default]
+    Warn,
+    /// Fails the job.
+    Fail,
+}
+
+/// The metadata of a track as hooks see it.
+#[derive(Debug, Clone, Serialize)]
+pub struct HookMetadata {
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub album_artists: Vec<String>,
+    pub year: Option<i32>,
+    pub number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub duration_ms: i32,
+}
+
+impl From<&TrackMetadata> for HookMetadata {
+    fn from(metadata: &TrackMetadata) -> Self {
+        HookMetadata {
+            title: metadata.track_name.clone(),
+            artists: metadata.artists.iter().map(|artist| artist.name.clone()).collect(),
+            album: metadata.album.name.clone(),
+            album_artists: metadata.album.artists.iter().map(|artist| artist.name.clone()).collect(),
+            year: metadata.album.year,
+            number: metadata.number,
+            disc_number: metadata.disc_number,
+            duration_ms: metadata.duration,
+        }
+    }
+}
+
+/// What a hook gets, external commands read it as JSON from their stdin.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum HookPayload {
+    Track {
+        #[serde(flatten)]
+        report: TrackReport,
+        metadata: Box<HookMetadata>,
+    },
+    Job {
+        report: JobReport,
+    },
+}
+
+impl HookPayload {
+    pub fn event(&self) -> HookEvent {
+        match self {
+            HookPayload::Track { .. } => HookEvent::Track,
+            HookPayload::Job { .. } => HookEvent::Job,
+        }
+    }
+}
+
+/// Runs an external program, like `beet import`, with the payload on its stdin. A non-zero
+/// exit status counts as a failure.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandHook {
+    pub event: HookEvent,
+    pub program: String,
+    pub args: Vec<String>,
+    /// The program is killed when it runs longer, 60 seconds by default.
+    pub timeout: Duration,
+    /// How often a failed run is tried again.
+    pub retries: u32,
+    pub on_failure: HookFailure,
+}
+
+impl CommandHook {
+    pub fn new(event: HookEvent, program: &str, args: &[&str]) -> Self {
+        CommandHook {
+            event,
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout: Duration::from_secs(60),
+            retries: 0,
+            on_failure: HookFailure::default(),
+        }
+    }
+
+    async fn run(&self, payload: &[u8]) -> Result<()> {
+        let mut child = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take();
+        let write = async move {
+            // Programs which don't read the payload close the pipe early, that's fine
+            if let Some(mut stdin) = stdin {
+                let _ = stdin.write_all(payload).await;
+            }
+        };
+        let (_, output) = tokio::join!(write, child.wait_with_output());
+        let output = output?;
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "{} exited with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Runs an async function in the process.
+#[derive(Clone)]
+pub struct CallbackHook {
+    pub event: HookEvent,
+    pub callback: HookCallback,
+    /// The callback is dropped when it runs longer, 60 seconds by default.
+    pub timeout: Duration,
+    pub retries: u32,
+    pub on_failure: HookFailure,
+}
+
+impl CallbackHook {
+    pub fn new<F, Fut>(event: HookEvent, callback: F) -> Self
+    where
+        F: Fn(HookPayload) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        CallbackHook {
+            event,
+            callback: Arc::new(move |payload| Box::pin(callback(payload))),
+            timeout: Duration::from_secs(60),
+            retries: 0,
+            on_failure: HookFailure::default(),
+        }
+    }
+}
+
+impl fmt::Debug for CallbackHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallbackHook")
+            .field("event", &self.event)
+            .field("timeout", &self.timeout)
+            .field("retries", &self.retries)
+            .field("on_failure", &self.on_failure)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Post-processing which runs after every track and after every job, in the order the hooks
+/// were added: commands first, then callbacks.
+///
+/// Callbacks can't be serialized, so jobs resumed from a checkpoint only run the commands.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Hooks {
+    pub commands: Vec<CommandHook>,
+    #[serde(skip)]
+    pub callbacks: Vec<CallbackHook>,
+}
+
+impl Hooks {
+    pub fn command(mut self, hook: CommandHook) -> Self {
+        self.commands.push(hook);
+        self
+    }
+
+    pub fn callback(mut self, hook: CallbackHook) -> Self {
+        self.callbacks.push(hook);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty() && self.callbacks.is_empty()
+    }
+
+    /// Runs every hook of the payload's event, an error means a hook failed with `HookFailure::Fail`.
+    pub(crate) async fn run(&self, payload: HookPayload) -> Result<()> {
+        let event = payload.event();
+
+        let commands: Vec<&CommandHook> = self.commands.iter().filter(|hook| hook.event == event).collect();
+        if !commands.is_empty() {
+            let json = serde_json::to_vec(&payload)?;
+            for hook in commands {
+                let result = attempt(hook.timeout, hook.retries, || hook.run(&json)).await;
+                handle(&hook.program, hook.on_failure, result)?;
+            }
+        }
+
+        for hook in self.callbacks.iter().filter(|hook| hook.event == event) {
+            let result = attempt(hook.timeout, hook.retries, || (hook.callback)(payload.clone())).await;
+            handle("callback", hook.on_failure, result)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the hook until it succeeds, at most `retries + 1` times.
+async fn attempt<F, Fut>(timeout: Duration, retries: u32, run: F) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut tries = 0;
+    loop {
+        let result = match tokio::time::timeout(timeout, run()).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("timed out after {:?}", timeout)),
+        };
+        match result {
+            Err(e) if tries < retries => {
+                tries += 1;
+                tracing::warn!("Hook failed, retrying ({}/{}): {}", tries, retries, e);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn handle(name: &str, on_failure: HookFailure, result: Result<()>) -> Result<()> {
+    let Err(e) = result else {
+        return Ok(());
+    };
+
+    match on_failure {
+        HookFailure::Ignore => {
+            tracing::debug!("Hook {} failed: {}", name, e);
+            Ok(())
+        }
+        HookFailure::Warn => {
+            tracing::warn!("Hook {} failed: {}", name, e);
+            Ok(())
+        }
+        HookFailure::Fail => Err(anyhow::anyhow!("Hook {} failed: {}", name, e)),
+    }
+}
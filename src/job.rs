@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use librespot::core::spotify_id::SpotifyId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::track::parse_uri_or_url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    #[default]
+    Running,
+    Paused,
+    Cancelled,
+}
+
+#[derive(Debug, Default)]
+struct PauseState {
+    paused: Mutex<bool>,
+    condvar: Condvar,
+}
+
+/// Lets the player thread of a track block while its job is paused.
+#[derive(Debug, Clone)]
+pub struct PauseGate {
+    pause: Arc<PauseState>,
+    cancel: CancellationToken,
+}
+
+impl PauseGate {
+    /// Blocks the current thread until the job is resumed or the track is cancelled.
+    pub fn wait_blocking(&self) {
+        let mut paused = self.pause.paused.lock().unwrap();
+        while *paused && !self.cancel.is_cancelled() {
+            // Track cancellation doesn't wake the condvar, so check for it every now and then
+            paused = self
+                .pause
+                .condvar
+                .wait_timeout(paused, Duration::from_millis(250))
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+/// A handle to a running download job, used to pause, resume or cancel the whole job
+/// or to cancel single tracks of it.
+///
+/// Pass a clone of the handle in the `DownloadOptions` and keep one around to control the job.
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    token: CancellationToken,
+    tracks: Arc<Mutex<HashMap<SpotifyId, CancellationToken>>>,
+    pause: Arc<PauseState>,
+    state: Arc<watch::Sender<JobState>>,
+}
+
+impl Default for JobHandle {
+    fn default() -> Self {
+        JobHandle {
+            token: CancellationToken::new(),
+            tracks: Arc::default(),
+            pause: Arc::default(),
+            state: Arc::new(watch::channel(JobState::Running).0),
+        }
+    }
+}
+
+impl JobHandle {
+    pub fn new() -> Self {
+        JobHandle::default()
+    }
+
+    /// Cancels every track of the job which isn't downloaded yet.
+    pub fn cancel(&self) {
+        self.token.cancel();
+        self.state.send_replace(JobState::Cancelled);
+        self.pause.condvar.notify_all();
+    }
+
+    /// Stops the job from starting new tracks and holds the decoding of the running ones.
+    pub fn pause(&self) {
+        if self.is_cancelled() {
+            return;
+        }
+        *self.pause.paused.lock().unwrap() = true;
+        self.state.send_replace(JobState::Paused);
+    }
+
+    pub fn resume(&self) {
+        if self.is_cancelled() {
+            return;
+        }
+        *self.pause.paused.lock().unwrap() = false;
+        self.pause.condvar.notify_all();
+        self.state.send_replace(JobState::Running);
+    }
+
+    pub fn state(&self) -> JobState {
+        *self.state.borrow()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state() == JobState::Paused
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Cancels a single track of the job by its uri or url. Tracks which haven't started yet
+    /// will be skipped once the job reaches them.
+    pub fn cancel_track(&self, track: &str) -> Result<()> {
+        let id = parse_uri_or_url(track).ok_or(anyhow::anyhow!("Invalid track"))?;
+        self.track_token(id).cancel();
+        Ok(())
+    }
+
+    /// Completes once the whole job is cancelled.
+    pub(crate) async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<JobState> {
+        self.state.subscribe()
+    }
+
+    pub(crate) fn pause_gate(&self, cancel: CancellationToken) -> PauseGate {
+        PauseGate {
+            pause: Arc::clone(&self.pause),
+            cancel,
+        }
+    }
+
+    /// Waits until the job isn't paused anymore or the track got cancelled.
+    pub(crate) async fn wait_while_paused(&self, cancel: &CancellationToken) {
+        let mut state = self.subscribe();
+        tokio::select! {
+            _ = state.wait_for(|state| *state != JobState::Paused) => {}
+            _ = cancel.cancelled() => {}
+        }
+    }
+
+    pub(crate) fn track_token(&self, id: SpotifyId) -> CancellationToken {
+        let mut tracks = self.tracks.lock().unwrap();
+        tracks
+            .entry(id)
+            .or_insert_with(|| self.token.child_token())
+            .clone()
+    }
+}
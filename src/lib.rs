@@ -1,6 +1,5 @@
-use std::{fs, path::PathBuf, sync::Arc};
+use std::{fs, path::{Path, PathBuf}, sync::Arc};
 use anyhow::Result;
-use download::DownloadOptions;
 use futures::SinkExt;
 use librespot::core::session::Session;
 use tokio::sync::{broadcast, Mutex};
@@ -13,17 +12,67 @@ mod channel_sink;
 mod track;
 mod encoder;
 mod download;
+mod manifest;
+mod sync;
+mod job;
+mod report;
+mod checkpoint;
+mod template;
+mod sanitize;
+mod plan;
+mod playlist_file;
+mod album_file;
+mod pacing;
+mod quality;
+mod ogg_file;
+mod space;
+mod queue;
+mod memory;
+mod watchdog;
+mod audio_check;
+mod storage;
+mod archive;
+mod hooks;
+mod transcode;
 
 use crate::{
     session::create_session,
-    track::get_tracks,
+    track::{get_tracks, parse_uri_or_url, Playlist, TrackCollection},
     download::Downloader,
-    encoder::Format
+    checkpoint::Checkpoint,
+    encoder::PARTIAL_EXTENSION,
+    manifest::{Manifest, MANIFEST_FILE_NAME},
+    pacing::Pacer
 };
 
-lazy_static::lazy_static! {
-    static ref STOP_FLAG: Mutex<bool> = Mutex::new(false);
-}
+#[cfg(feature = "s3")]
+pub use crate::storage::{S3Config, S3Storage};
+#[cfg(feature = "webdav")]
+pub use crate::storage::{WebDavConfig, WebDavStorage};
+
+pub use crate::{
+    download::{DownloadOptions, OutputFormat},
+    encoder::{EncoderSettings, Format},
+    job::{JobHandle, JobState},
+    report::{JobReport, TrackReport, TrackStatus},
+    manifest::{ManifestEntry, ManifestSource},
+    sync::{SyncRemoval, SyncSummary},
+    sanitize::{SanitizeProfile, Sanitizer},
+    template::PathTemplates,
+    plan::{Collision, CollisionPolicy},
+    playlist_file::PlaylistFiles,
+    pacing::{Pacing, TrackQuota},
+    quality::SourceQuality,
+    space::{SpaceAction, SpaceCheck, SpaceEstimate},
+    memory::MemoryBudget,
+    watchdog::Watchdog,
+    audio_check::{AudioCheck, AudioCheckAction, AudioIssue},
+    storage::{LocalStorage, MemoryStorage, Storage},
+    archive::{Archive, ArchiveFormat},
+    hooks::{CallbackHook, CommandHook, HookCallback, HookEvent, HookFailure, HookMetadata, HookPayload, Hooks},
+    transcode::{transcode, TranscodeOptions, TranscodeSource, TranscodeSummary},
+    queue::{DownloadQueue, JobId, QueuedJob, QueuedJobState, QueuedTrack, QueuedTrackState}
+};
 
 fn destination_folder(folder_path: PathBuf) -> Result<String> {
     if !folder_path.exists() {
@@ -35,6 +84,22 @@ fn destination_folder(folder_path: PathBuf) -> Result<String> {
     Ok(folder_str)
 }
 
+/// Removes the `.part` files left behind by downloads which were interrupted while writing.
+fn remove_partial_files(folder: &Path) -> Result<()> {
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            remove_partial_files(&path)?;
+        } else if path.extension().is_some_and(|extension| extension == PARTIAL_EXTENSION) {
+            tracing::info!("Removing leftover partial file: {:?}", path);
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub enum DownloadStateOpts {
     MessageSender(String),
@@ -50,7 +115,10 @@ pub struct SpotifyDownloader {
     output_folder: String,
     session: Session,
     state: Arc<Mutex<DownloadState>>,
-
+    manifest: Arc<Mutex<Manifest>>,
+    pacer: Arc<Pacer>,
+    storage: Arc<dyn Storage>,
+    queue: DownloadQueue,
 }
 
 impl SpotifyDownloader {
@@ -60,8 +128,29 @@ impl SpotifyDownloader {
         password: &str,
         ws_url: Option<String>
     ) -> Result<Self> {
+        Self::with_storage(output_folder_name, username, password, ws_url, Arc::new(LocalStorage)).await
+    }
 
-        let output_folder = destination_folder(output_folder_name)?;
+    /// Like `new`, writing every file, including the manifest, to the storage instead of the
+    /// local file system. With storages which aren't a file system the output folder is a
+    /// prefix of every key.
+    pub async fn with_storage(
+        output_folder_name: PathBuf,
+        username: &str,
+        password: &str,
+        ws_url: Option<String>,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self> {
+        let output_folder = if storage.is_local() {
+            let output_folder = destination_folder(output_folder_name)?;
+            remove_partial_files(Path::new(&output_folder))?;
+            output_folder
+        } else {
+            output_folder_name.to_string_lossy().into_owned()
+        };
+
+        let manifest = Manifest::load(Arc::clone(&storage), Path::new(&output_folder).join(MANIFEST_FILE_NAME)).await?;
+        let manifest = Arc::new(Mutex::new(manifest));
 
         let session = create_session(&username, &password).await?;
 
@@ -87,11 +176,23 @@ impl SpotifyDownloader {
 
         }
 
+        let pacer = Arc::new(Pacer::default());
+        let queue = DownloadQueue::new(
+            session.clone(),
+            Arc::clone(&state),
+            Arc::clone(&manifest),
+            Arc::clone(&pacer),
+            Arc::clone(&storage),
+        );
+
         Ok(Self {
             output_folder,
             session,
             state,
-
+            manifest,
+            pacer,
+            storage,
+            queue,
         })
     }
 
@@ -101,36 +202,216 @@ impl SpotifyDownloader {
         parallel: Option<usize>,
         compression: Option<u32>,
         format: &str,
-    ) -> Result<()> {
+    ) -> Result<JobReport> {
 
         let format = match format {
             "mp3" => Format::Mp3,
             "flac" => Format::Flac,
+            "ogg" => Format::Ogg,
             _ => panic!("unsupported format provided")
         };
 
         let parallel = parallel.unwrap_or(5);
         let compression = compression.unwrap_or(4);
 
-        let tracks = get_tracks(track_url, &self.session).await?;
-
-        let downloader = Downloader::new(&self.session, Arc::clone(&self.state));
-        downloader.download_tracks(
-            tracks,
+        self.download_with_options(
+            track_url,
             &DownloadOptions::new(Some(&self.output_folder), Some(compression), parallel, format),
-        ).await?;
+        ).await
+    }
+
+    /// The default options for downloading into the output folder with the given format.
+    pub fn download_options(&self, format: Format) -> DownloadOptions {
+        DownloadOptions::new(Some(&self.output_folder), Some(4), 5, format)
+    }
+
+    pub async fn download_with_options(
+        &self,
+        track_url: Vec<String>,
+        options: &DownloadOptions,
+    ) -> Result<JobReport> {
+
+        let tracks = get_tracks(track_url, &self.session).await?;
 
-        println!("all tracks were downloaded!");
+        let shutdown_listener = self.cancel_on_shutdown(options.job.clone()).await;
+
+        let downloader = Downloader::new(
+            &self.session,
+            Arc::clone(&self.state),
+            Arc::clone(&self.manifest),
+            Arc::clone(&self.pacer),
+            Arc::clone(&self.storage),
+        )
+        .in_queue(self.queue.slot());
+        let report = downloader.download_tracks(tracks, options).await;
+
+        shutdown_listener.abort();
+        let report = report?;
+
+        // The plan of a dry run is returned for the caller to show
+        if report.plan.is_some() {
+            println!("the dry run is done!");
+        } else if report.cancelled() {
+            println!("the download was cancelled!");
+        } else if report.failed().next().is_some() {
+            println!("{} tracks failed to download!", report.failed().count());
+        } else {
+            println!("all tracks were downloaded!");
+        }
 
         let state = &self.state.lock().await;
 
         state.sender.send(DownloadStateOpts::SocketCloser)?;
 
-        Ok(())
+        Ok(report)
+    }
+
+    /// The queue shared by every job of this downloader, limiting how many tracks are
+    /// downloaded at once over all of them. Only jobs added with `submit` are listed in it.
+    pub fn queue(&self) -> &DownloadQueue {
+        &self.queue
+    }
+
+    /// Adds a job to the queue, it starts as soon as the queue has a free slot for its tracks.
+    pub fn submit(&self, track_url: Vec<String>, options: DownloadOptions, priority: i32) -> JobId {
+        self.queue.submit(track_url, options, priority)
+    }
+
+    /// Resolves the tracks and estimates the size of the job, compared with the free space
+    /// at the destination, without downloading anything.
+    pub async fn estimate_space(&self, track_url: Vec<String>, options: &DownloadOptions) -> Result<SpaceEstimate> {
+        let tracks = get_tracks(track_url, &self.session).await?;
+
+        let downloader = Downloader::new(
+            &self.session,
+            Arc::clone(&self.state),
+            Arc::clone(&self.manifest),
+            Arc::clone(&self.pacer),
+            Arc::clone(&self.storage),
+        )
+        .in_queue(self.queue.slot());
+        let planned = downloader.plan_tracks(tracks, options).await?;
+
+        downloader.estimate_space(&planned, options)
+    }
+
+    /// Resumes an interrupted job from the state file it was saving its progress to
+    /// (see `DownloadOptions::state_file`), downloading only the unfinished tracks.
+    pub async fn resume(&self, state_file: &Path, job: JobHandle) -> Result<JobReport> {
+        let checkpoint = Checkpoint::load(state_file).await?;
+
+        let shutdown_listener = self.cancel_on_shutdown(job.clone()).await;
+
+        let downloader = Downloader::new(
+            &self.session,
+            Arc::clone(&self.state),
+            Arc::clone(&self.manifest),
+            Arc::clone(&self.pacer),
+            Arc::clone(&self.storage),
+        )
+        .in_queue(self.queue.slot());
+        let report = downloader.resume(checkpoint, job).await;
+
+        shutdown_listener.abort();
+
+        report
+    }
+
+    /// Cancels every running job of this downloader.
+    pub async fn shutdown(&self) {
+        let state = self.state.lock().await;
+        // Sending only fails if there is no job or websocket listening, which is fine
+        let _ = state.sender.send(DownloadStateOpts::ShutdownSignal);
+    }
+
+    async fn cancel_on_shutdown(&self, job: JobHandle) -> tokio::task::JoinHandle<()> {
+        cancel_on_shutdown(&self.state, job).await
+    }
+
+    /// Mirrors a playlist into its output folder: downloads the tracks which were added to it
+    /// and deletes or archives the ones which left it since the last sync.
+    pub async fn sync_playlist(
+        &self,
+        playlist_url: &str,
+        options: &DownloadOptions,
+        removal: SyncRemoval,
+    ) -> Result<SyncSummary> {
+        let id = parse_uri_or_url(playlist_url).ok_or(anyhow::anyhow!("Invalid playlist"))?;
+        if !Playlist::is_playlist(id, &self.session).await {
+            return Err(anyhow::anyhow!("{} is not a playlist", playlist_url));
+        }
+
+        let tracks = Playlist::from_id(id).get_tracks(&self.session).await;
+
+        let shutdown_listener = self.cancel_on_shutdown(options.job.clone()).await;
+
+        let downloader = Downloader::new(
+            &self.session,
+            Arc::clone(&self.state),
+            Arc::clone(&self.manifest),
+            Arc::clone(&self.pacer),
+            Arc::clone(&self.storage),
+        )
+        .in_queue(self.queue.slot());
+        let summary = sync::sync_playlist(&downloader, id, tracks, options, removal).await;
+
+        shutdown_listener.abort();
+        let summary = summary?;
+
+        tracing::info!("Synced playlist {}: {}", playlist_url, summary);
+
+        Ok(summary)
+    }
+
+    /// Re-encodes the files of a library written by this downloader into a new tree in another
+    /// format, without downloading them again.
+    pub async fn transcode(&self, options: &TranscodeOptions) -> Result<TranscodeSummary> {
+        let summary = transcode::transcode(Arc::clone(&self.storage), options).await?;
+
+        tracing::info!("Transcoded {:?}: {}", options.library, summary);
+
+        Ok(summary)
+    }
+
+    /// Looks up the manifest entry of a previously downloaded track by its uri or url.
+    pub async fn manifest_entry(&self, track: &str) -> Result<Option<ManifestEntry>> {
+        let id = parse_uri_or_url(track).ok_or(anyhow::anyhow!("Invalid track"))?;
+        Ok(self.manifest.lock().await.get(&id).cloned())
+    }
+
+    /// Whether the track was downloaded into the output folder before.
+    pub async fn has_track(&self, track: &str) -> Result<bool> {
+        let id = parse_uri_or_url(track).ok_or(anyhow::anyhow!("Invalid track"))?;
+        Ok(self.manifest.lock().await.contains(&id))
+    }
+
+    /// The path(s) the track was written to, if it was downloaded before.
+    pub async fn track_location(&self, track: &str) -> Result<Option<Vec<PathBuf>>> {
+        let id = parse_uri_or_url(track).ok_or(anyhow::anyhow!("Invalid track"))?;
+        Ok(self.manifest.lock().await.locate(&id))
     }
 }
 
 
+/// Cancels the job once `SpotifyDownloader::shutdown` is called, until the returned task is
+/// aborted.
+pub(crate) async fn cancel_on_shutdown(state: &Mutex<DownloadState>, job: JobHandle) -> tokio::task::JoinHandle<()> {
+    let mut receiver = state.lock().await.sender.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(DownloadStateOpts::ShutdownSignal) => {
+                    job.cancel();
+                    break;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+                _ => {}
+            }
+        }
+    })
+}
+
 pub async fn verify_login( username: &str, password: &str) -> Result<()> {
     let _session = create_session(&username, &password).await?;
     Ok(())
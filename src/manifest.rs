@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use librespot::core::spotify_id::SpotifyId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::encoder::{EncoderSettings, Format};
+use crate::quality::SourceQuality;
+use crate::storage::Storage;
+
+pub const MANIFEST_FILE_NAME: &str = ".spotify-dl-manifest.json";
+
+/// The collection a track was downloaded as part of.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "id", rename_all = "lowercase")]
+pub enum ManifestSource {
+    Album(String),
+    Playlist(String),
+}
+
+impl ManifestSource {
+    pub fn album(id: SpotifyId) -> Result<Self> {
+        Ok(ManifestSource::Album(id.to_base62()?))
+    }
+
+    pub fn playlist(id: SpotifyId) -> Result<Self> {
+        Ok(ManifestSource::Playlist(id.to_base62()?))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub paths: Vec<PathBuf>,
+    pub format: Format,
+    pub encoder: EncoderSettings,
+    /// The bitrate of the stream fetched from spotify, missing for entries from older versions.
+    #[serde(default)]
+    pub source_quality: Option<SourceQuality>,
+    pub file_size: u64,
+    /// Hex encoded sha256 of the written file.
+    pub checksum: String,
+    pub source: Option<ManifestSource>,
+    /// Seconds since the unix epoch at which the entry was recorded.
+    pub timestamp: u64,
+}
+
+impl ManifestEntry {
+    pub fn new(
+        path: PathBuf,
+        format: Format,
+        encoder: EncoderSettings,
+        source_quality: SourceQuality,
+        content: &[u8],
+        source: Option<ManifestSource>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        ManifestEntry {
+            paths: vec![path],
+            format,
+            encoder,
+            source_quality: Some(source_quality),
+            file_size: content.len() as u64,
+            checksum: hex::encode(Sha256::digest(content)),
+            source,
+            timestamp,
+        }
+    }
+}
+
+/// A JSON backed record of every track the library has written, keyed by the URI of the
+/// track's `SpotifyId` so episodes keep their type. A track gets one entry per album or
+/// playlist it was downloaded as part of and per format it was written in.
+#[derive(Debug)]
+pub struct Manifest {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    entries: HashMap<String, Vec<ManifestEntry>>,
+}
+
+/// What older versions wrote: a single entry per track, keyed by the base62 id.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEntries {
+    Entries(Vec<ManifestEntry>),
+    Entry(ManifestEntry),
+}
+
+impl Manifest {
+    /// Loads the manifest stored at `path`, starting with an empty one if the file doesn't exist yet.
+    pub async fn load<P: AsRef<Path>>(storage: Arc<dyn Storage>, path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let stored: HashMap<String, StoredEntries> = match storage.read(&path).await? {
+            Some(content) => serde_json::from_slice(&content)?,
+            None => HashMap::new(),
+        };
+
+        let mut manifest = Manifest {
+            storage,
+            path,
+            entries: HashMap::new(),
+        };
+        for (key, stored) in stored {
+            let key = if key.starts_with("spotify:") {
+                key
+            } else {
+                format!("spotify:track:{}", key)
+            };
+            let entries = match stored {
+                StoredEntries::Entries(entries) => entries,
+                StoredEntries::Entry(entry) => vec![entry],
+            };
+            manifest.entries.entry(key).or_default().extend(entries);
+        }
+
+        Ok(manifest)
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let content = serde_json::to_vec_pretty(&self.entries)?;
+        self.storage.write(&self.path, &content).await
+    }
+
+    /// The most recently recorded entry of the track.
+    pub fn get(&self, id: &SpotifyId) -> Option<&ManifestEntry> {
+        self.entries
+            .get(&id.to_uri().ok()?)?
+            .iter()
+            .max_by_key(|entry| entry.timestamp)
+    }
+
+    /// Whether the track was downloaded before.
+    pub fn contains(&self, id: &SpotifyId) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// The output path(s) of the track across all its entries, if it was downloaded before.
+    pub fn locate(&self, id: &SpotifyId) -> Option<Vec<PathBuf>> {
+        let entries = self.entries.get(&id.to_uri().ok()?)?;
+
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for path in entries.iter().flat_map(|entry| &entry.paths) {
+            if !paths.contains(path) {
+                paths.push(path.clone());
+            }
+        }
+        Some(paths)
+    }
+
+    /// The paths the track was written to in the given format, across all its entries.
+    pub fn locate_format(&self, id: &SpotifyId, format: Format) -> Vec<PathBuf> {
+        let Some(entries) = id.to_uri().ok().and_then(|uri| self.entries.get(&uri)) else {
+            return Vec::new();
+        };
+        entries
+            .iter()
+            .filter(|entry| entry.format == format)
+            .flat_map(|entry| entry.paths.iter().cloned())
+            .collect()
+    }
+
+    /// The entry of the track recorded for the given album or playlist and format.
+    pub fn get_mut(
+        &mut self,
+        id: &SpotifyId,
+        source: Option<&ManifestSource>,
+        format: Format,
+    ) -> Option<&mut ManifestEntry> {
+        self.entries
+            .get_mut(&id.to_uri().ok()?)?
+            .iter_mut()
+            .find(|entry| entry.source.as_ref() == source && entry.format == format)
+    }
+
+    /// Records the entry, the paths of an existing entry for the same source and format are kept.
+    pub fn insert(&mut self, id: &SpotifyId, mut entry: ManifestEntry) -> Result<()> {
+        let entries = self.entries.entry(id.to_uri()?).or_default();
+
+        match entries
+            .iter_mut()
+            .find(|existing| existing.source == entry.source && existing.format == entry.format)
+        {
+            Some(existing) => {
+                for path in std::mem::take(&mut existing.paths) {
+                    if !entry.paths.contains(&path) {
+                        entry.paths.push(path);
+                    }
+                }
+                *existing = entry;
+            }
+            None => entries.push(entry),
+        }
+        Ok(())
+    }
+
+    /// Removes the entry of the track recorded for the given album or playlist and format.
+    pub fn remove(&mut self, id: &SpotifyId, source: Option<&ManifestSource>, format: Format) -> Option<ManifestEntry> {
+        let uri = id.to_uri().ok()?;
+        let entries = self.entries.get_mut(&uri)?;
+        let index = entries
+            .iter()
+            .position(|entry| entry.source.as_ref() == source && entry.format == format)?;
+        let entry = entries.remove(index);
+        if entries.is_empty() {
+            self.entries.remove(&uri);
+        }
+        Some(entry)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (SpotifyId, &ManifestEntry)> {
+        self.entries.iter().flat_map(|(uri, entries)| {
+            let id = SpotifyId::from_uri(uri).ok();
+            entries.iter().filter_map(move |entry| Some((id?, entry)))
+        })
+    }
+
+    /// All entries which were downloaded as part of the given album or playlist.
+    pub fn entries_from(&self, source: &ManifestSource) -> Vec<(SpotifyId, ManifestEntry)> {
+        self.entries()
+            .filter(|(_, entry)| entry.source.as_ref() == Some(source))
+            .map(|(id, entry)| (id, entry.clone()))
+            .collect()
+    }
+}
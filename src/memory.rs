@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::encoder::{EncoderSettings, Format};
+use crate::quality::SourceQuality;
+use crate::space;
+use crate::track::TrackMetadata;
+
+/// Bytes per second of the samples buffered while a track plays: 44.1 kHz stereo, one `i32` per sample.
+const SAMPLE_BYTES_PER_SECOND: u64 = 44100 * 2 * 4;
+/// The semaphore counts kibibytes so large budgets fit into its permits.
+const UNIT: u64 = 1024;
+
+/// Runs as many tracks at once as fit into a memory budget, estimating what every track
+/// buffers from its duration, instead of a fixed number of tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryBudget {
+    /// The memory all running tracks of a job may buffer together, 1 GiB by default.
+    pub bytes: u64,
+    /// Upper bound on the tracks running at once, however short they are. Replaces
+    /// `DownloadOptions::parallel` while the budget is used.
+    pub max_parallel: usize,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget {
+            bytes: 1024 * 1024 * 1024,
+            max_parallel: 16,
+        }
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(bytes: u64) -> Self {
+        MemoryBudget {
+            bytes,
+            ..MemoryBudget::default()
+        }
+    }
+}
+
+/// Estimates what downloading a track buffers: the decoded samples, unless the original file
+/// is passed through, and the encoded file.
+pub fn estimate_track_memory(metadata: &TrackMetadata, format: Format, settings: EncoderSettings, quality: SourceQuality) -> u64 {
+    let encoded = space::estimate_track_size(metadata, format, settings, quality);
+    if format == Format::Ogg {
+        return encoded;
+    }
+
+    estimate_samples_memory(metadata) + encoded
+}
+
+/// Estimates the decoded samples of a track.
+pub fn estimate_samples_memory(metadata: &TrackMetadata) -> u64 {
+    let milliseconds = metadata.duration.max(0) as u64;
+    milliseconds * SAMPLE_BYTES_PER_SECOND / 1000
+}
+
+/// Hands out the memory budget of a job to its running work units.
+pub struct MemoryGate {
+    semaphore: Semaphore,
+    units: u32,
+}
+
+impl MemoryGate {
+    pub fn new(budget: &MemoryBudget) -> Self {
+        let units = (budget.bytes / UNIT).clamp(1, u64::from(u32::MAX >> 3)) as u32;
+        MemoryGate {
+            semaphore: Semaphore::new(units as usize),
+            units,
+        }
+    }
+
+    /// Waits until `bytes` of the budget are free. Units needing more than the whole budget
+    /// wait for all of it and run alone.
+    pub async fn reserve(&self, bytes: u64) -> SemaphorePermit<'_> {
+        let units = bytes.div_ceil(UNIT).clamp(1, u64::from(self.units)) as u32;
+        if units > self.semaphore.available_permits() as u32 {
+            tracing::debug!("Waiting for {} KiB of the memory budget", units);
+        }
+
+        // The semaphore is never closed
+        self.semaphore.acquire_many(units).await.unwrap()
+    }
+}
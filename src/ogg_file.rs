@@ -0,0 +1,124 @@
+use std::io::{Cursor, Read};
+
+use anyhow::Result;
+use librespot::audio::{AudioDecrypt, AudioFile};
+use librespot::core::audio_key::AudioKey;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+
+use crate::quality::{self, SourceQuality};
+
+/// Spotify puts its own header in front of the Ogg stream of every file.
+const SPOTIFY_HEADER_SIZE: usize = 0xa7;
+const VENDOR: &str = "spotify-dl-lib";
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The encrypted original file of a track and the key to decrypt it.
+pub struct OriginalFile {
+    file: AudioFile,
+    key: AudioKey,
+    pub quality: SourceQuality,
+}
+
+impl OriginalFile {
+    /// Opens the original Ogg Vorbis file in the preferred quality, or the next best one.
+    pub async fn open(session: &Session, id: SpotifyId, preferred: SourceQuality) -> Result<Self> {
+        let (quality, id, file_id) = quality::resolve_file(session, id, preferred).await?;
+
+        let bytes_per_second = quality.kbps() as usize * 1000 / 8;
+        let file = AudioFile::open(session, file_id, bytes_per_second, true)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to open the audio file"))?;
+        let key = session
+            .audio_key()
+            .request(id, file_id)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get the audio key"))?;
+
+        Ok(OriginalFile { file, key, quality })
+    }
+
+    /// Downloads and decrypts the whole file on the current thread and strips Spotify's header.
+    ///
+    /// `on_chunk` is called with the bytes read so far and the size of the file, returning `false`
+    /// stops the download and makes this return `None`.
+    pub fn read_blocking(self, mut on_chunk: impl FnMut(usize, usize) -> bool) -> Result<Option<Vec<u8>>> {
+        let total = self.file.get_stream_loader_controller().len();
+        let mut decrypted = AudioDecrypt::new(self.key, self.file);
+
+        let mut content = Vec::with_capacity(total);
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        loop {
+            let read = decrypted.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            content.extend_from_slice(&chunk[..read]);
+
+            if !on_chunk(content.len(), total) {
+                return Ok(None);
+            }
+        }
+
+        if content.get(SPOTIFY_HEADER_SIZE..SPOTIFY_HEADER_SIZE + 4) != Some(b"OggS".as_slice()) {
+            return Err(anyhow::anyhow!("The decrypted file is not an Ogg stream"));
+        }
+        content.drain(..SPOTIFY_HEADER_SIZE);
+
+        Ok(Some(content))
+    }
+}
+
+/// Replaces the Vorbis comment header of the Ogg stream, leaving every other packet untouched.
+pub fn set_comments(stream: &[u8], comments: &[(&str, String)]) -> Result<Vec<u8>> {
+    let mut reader = PacketReader::new(Cursor::new(stream));
+    let mut packets = Vec::new();
+    while let Some(packet) = reader.read_packet()? {
+        packets.push(packet);
+    }
+
+    let mut writer = PacketWriter::new(Vec::with_capacity(stream.len()));
+    let count = packets.len();
+    for (index, packet) in packets.into_iter().enumerate() {
+        let end = if index + 1 == count {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let serial = packet.stream_serial();
+        let absgp = packet.absgp_page();
+
+        // The second packet of a Vorbis stream is its comment header
+        let data = if index == 1 {
+            comment_header(comments)
+        } else {
+            packet.data
+        };
+
+        writer.write_packet(data.into_boxed_slice(), serial, end, absgp)?;
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn comment_header(comments: &[(&str, String)]) -> Vec<u8> {
+    let mut header = vec![0x03];
+    header.extend_from_slice(b"vorbis");
+
+    header.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+    header.extend_from_slice(VENDOR.as_bytes());
+
+    header.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let comment = format!("{}={}", key, value);
+        header.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        header.extend_from_slice(comment.as_bytes());
+    }
+
+    // Framing bit
+    header.push(0x01);
+    header
+}
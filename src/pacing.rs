@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::quality::SourceQuality;
+
+/// Limits on how fast tracks are fetched, so a job looks less like a bulk download.
+/// Hitting a limit makes the job wait, it never fails because of one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pacing {
+    /// Minimum time between the starts of two tracks.
+    pub min_delay: Duration,
+    /// Up to this much random time is added to `min_delay` for every track.
+    pub jitter: Duration,
+    /// Cap on the audio bytes fetched per second over all running tracks.
+    pub max_bytes_per_second: Option<u64>,
+    /// Maximum number of tracks started within a rolling time window. The starts are only
+    /// counted in memory, so the window begins anew with every process.
+    pub quota: Option<TrackQuota>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackQuota {
+    pub tracks: usize,
+    pub window: Duration,
+}
+
+impl TrackQuota {
+    pub fn new(tracks: usize, window: Duration) -> Self {
+        TrackQuota { tracks, window }
+    }
+
+    pub fn per_day(tracks: usize) -> Self {
+        TrackQuota::new(tracks, Duration::from_secs(24 * 60 * 60))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Starts {
+    last: Option<Instant>,
+    /// Track starts within the longest quota window seen so far, including booked ones.
+    recent: VecDeque<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    available: f64,
+    updated: Option<Instant>,
+}
+
+/// Keeps track of the starts and fetched bytes of every job of a downloader, so the limits
+/// hold across jobs as well.
+#[derive(Debug, Default)]
+pub struct Pacer {
+    starts: std::sync::Mutex<Starts>,
+    bandwidth: std::sync::Mutex<Bucket>,
+}
+
+impl Pacer {
+    /// Waits until the next track may start, returns `false` if the track was cancelled meanwhile.
+    pub async fn wait_for_start(&self, pacing: &Pacing, cancel: &CancellationToken) -> bool {
+        let start = self.reserve_start(pacing);
+
+        let now = Instant::now();
+        if start > now {
+            tracing::info!("Pacing: waiting {:?} before starting the next track", start - now);
+            tokio::select! {
+                _ = tokio::time::sleep_until(start.into()) => {}
+                _ = cancel.cancelled() => {
+                    // Hands the slot in the quota back, the delay to the next start stays
+                    let mut starts = self.starts.lock().unwrap();
+                    if let Some(index) = starts.recent.iter().position(|recent| *recent == start) {
+                        starts.recent.remove(index);
+                    }
+                    return false;
+                }
+            }
+        }
+
+        !cancel.is_cancelled()
+    }
+
+    /// Works out when the next track may start and books that time, so the tracks line up one
+    /// after the other without anyone holding the lock while they wait.
+    fn reserve_start(&self, pacing: &Pacing) -> Instant {
+        let mut starts = self.starts.lock().unwrap();
+
+        let jitter = if pacing.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=pacing.jitter)
+        };
+
+        let now = Instant::now();
+        let mut start = now;
+
+        if let Some(last) = starts.last {
+            start = start.max(last + pacing.min_delay + jitter);
+        }
+
+        if let Some(quota) = pacing.quota {
+            while starts
+                .recent
+                .front()
+                .is_some_and(|recent| now.saturating_duration_since(*recent) >= quota.window)
+            {
+                starts.recent.pop_front();
+            }
+            if quota.tracks > 0 && starts.recent.len() >= quota.tracks {
+                let oldest = starts.recent[starts.recent.len() - quota.tracks];
+                start = start.max(oldest + quota.window);
+            }
+        }
+
+        starts.last = Some(start);
+        if pacing.quota.is_some() {
+            starts.recent.push_back(start);
+        }
+
+        start
+    }
+
+    /// A throttle for the player thread of a track fetched in `quality`, if the pacing caps the bandwidth.
+    pub fn throttle(
+        self: &Arc<Self>,
+        pacing: &Pacing,
+        quality: SourceQuality,
+        cancel: CancellationToken,
+    ) -> Option<Throttle> {
+        pacing.max_bytes_per_second.map(|bytes_per_second| Throttle {
+            pacer: Arc::clone(self),
+            bytes_per_second: bytes_per_second.max(1) as f64,
+            source_bytes_per_second: f64::from(quality.kbps()) * 1000.0 / 8.0,
+            cancel,
+            waited: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Takes the bytes out of the shared budget and returns how long to wait for them.
+    fn take(&self, bytes: f64, bytes_per_second: f64) -> Duration {
+        let mut bucket = self.bandwidth.lock().unwrap();
+        let now = Instant::now();
+
+        // Allows bursts of up to a second worth of bytes
+        let refill = bucket
+            .updated
+            .map_or(bytes_per_second, |updated| now.duration_since(updated).as_secs_f64() * bytes_per_second);
+        bucket.available = (bucket.available + refill).min(bytes_per_second) - bytes;
+        bucket.updated = Some(now);
+
+        if bucket.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.available / bytes_per_second)
+        }
+    }
+}
+
+/// Slows the player thread of a track down to the bandwidth cap.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    pacer: Arc<Pacer>,
+    bytes_per_second: f64,
+    /// Turns played audio into fetched bytes.
+    source_bytes_per_second: f64,
+    cancel: CancellationToken,
+    /// Nanoseconds spent waiting since the watchdog last asked, shared by the clones.
+    waited: Arc<AtomicU64>,
+}
+
+impl Throttle {
+    /// Accounts for the bytes fetched for `audio` worth of playback and blocks the current
+    /// thread until they fit under the cap.
+    pub fn wait_blocking(&self, audio: Duration) {
+        let mut wait = self
+            .pacer
+            .take(audio.as_secs_f64() * self.source_bytes_per_second, self.bytes_per_second);
+
+        // Sleep in steps so a cancelled track doesn't hang around
+        while !wait.is_zero() && !self.cancel.is_cancelled() {
+            let step = wait.min(Duration::from_millis(250));
+            std::thread::sleep(step);
+            wait -= step;
+            self.waited.fetch_add(step.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// The time spent waiting for the cap since the last call.
+    pub fn take_waited(&self) -> Duration {
+        Duration::from_nanos(self.waited.swap(0, Ordering::Relaxed))
+    }
+}
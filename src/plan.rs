@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use librespot::core::spotify_id::SpotifyId;
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::Manifest;
+use crate::quality::SourceQuality;
+use crate::sanitize::SanitizeProfile;
+use crate::space::SpaceEstimate;
+use crate::track::{Track, TrackMetadata};
+
+/// What happens when two different tracks of a job map to the same output path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    /// Adds ` (2)`, ` (3)`, ... to the file name of the later track.
+    #[default]
+    NumericSuffix,
+    /// Adds the base62 id of the later track to its file name.
+    AppendTrackId,
+    /// Doesn't download the later track.
+    Skip,
+    /// Refuses to start the job.
+    Error,
+}
+
+/// A path collision and how it was settled.
+#[derive(Debug, Clone, Serialize)]
+pub struct Collision {
+    /// The uri of the track which claimed the path first.
+    pub with: String,
+    /// The path both tracks mapped to.
+    pub path: PathBuf,
+    pub policy: CollisionPolicy,
+}
+
+/// A track of a job with its metadata and the path it will be written to.
+#[derive(Debug, Clone)]
+pub struct PlannedTrack {
+    pub track: Track,
+    pub metadata: TrackMetadata,
+    pub path: PathBuf,
+    /// The paths of the job's extra formats, in their order.
+    pub extra_paths: Vec<PathBuf>,
+    pub collision: Option<Collision>,
+}
+
+impl PlannedTrack {
+    pub fn new(track: Track, metadata: TrackMetadata, path: PathBuf) -> Self {
+        PlannedTrack {
+            track,
+            metadata,
+            path,
+            extra_paths: Vec::new(),
+            collision: None,
+        }
+    }
+
+    pub fn with_extra_paths(mut self, extra_paths: Vec<PathBuf>) -> Self {
+        self.extra_paths = extra_paths;
+        self
+    }
+
+    /// Whether the track was left out of the job because its path is taken.
+    pub fn is_skipped(&self) -> bool {
+        self.collision
+            .as_ref()
+            .is_some_and(|collision| collision.policy == CollisionPolicy::Skip)
+    }
+
+    /// Whether the same track shows up earlier in the job, so its path points to that file.
+    pub fn is_duplicate(&self) -> bool {
+        self.collision
+            .as_ref()
+            .is_some_and(|collision| self.track.id.to_uri().is_ok_and(|uri| uri == collision.with))
+    }
+}
+
+struct Claim {
+    id: SpotifyId,
+    /// Claimed by a track of this job rather than by a file from an earlier job.
+    in_job: bool,
+}
+
+impl Claim {
+    /// The same track downloaded again by a later job just replaces its file.
+    fn allows(&self, id: SpotifyId) -> bool {
+        self.id == id && !self.in_job
+    }
+}
+
+/// Finds the tracks whose output paths, including the paths of their extra formats, collide
+/// with each other or with files other tracks were written to before, and settles them with
+/// the given policy.
+pub fn resolve_collisions(
+    planned: &mut [PlannedTrack],
+    policy: CollisionPolicy,
+    profile: SanitizeProfile,
+    manifest: &Manifest,
+) -> Result<()> {
+    let mut claims = Claims {
+        profile,
+        claimed: HashMap::new(),
+    };
+    for (id, entry) in manifest.entries() {
+        for path in &entry.paths {
+            claims.claim(path, Claim { id, in_job: false });
+        }
+    }
+
+    for planned in planned.iter_mut() {
+        let main = planned.path.clone();
+        match claims.settle(&main, planned.track.id, policy)? {
+            Settled::Free => {}
+            Settled::Moved(path, collision) => {
+                // Extra files next to the main file move along with it
+                for extra in planned.extra_paths.iter_mut() {
+                    let Some(extension) = extra.extension().map(ToOwned::to_owned) else {
+                        continue;
+                    };
+                    if *extra == main.with_extension(&extension) {
+                        *extra = path.with_extension(extension);
+                    }
+                }
+                planned.path = path;
+                planned.collision = Some(collision);
+            }
+            Settled::Skipped(collision) => {
+                planned.collision = Some(collision);
+                continue;
+            }
+        }
+
+        for index in 0..planned.extra_paths.len() {
+            match claims.settle(&planned.extra_paths[index], planned.track.id, policy)? {
+                Settled::Free => {}
+                Settled::Moved(path, collision) => {
+                    planned.extra_paths[index] = path;
+                    planned.collision.get_or_insert(collision);
+                }
+                // A track isn't written in only some of its formats
+                Settled::Skipped(collision) => {
+                    planned.collision = Some(collision);
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// How a path of a planned track was settled.
+enum Settled {
+    Free,
+    /// Moved to a free path.
+    Moved(PathBuf, Collision),
+    /// The track is left out.
+    Skipped(Collision),
+}
+
+/// The paths claimed by earlier jobs and by the tracks of this job planned so far.
+struct Claims {
+    profile: SanitizeProfile,
+    claimed: HashMap<String, Claim>,
+}
+
+impl Claims {
+    fn key(&self, path: &Path) -> String {
+        let path = path.to_string_lossy();
+        if self.profile.is_case_insensitive() {
+            path.to_lowercase()
+        } else {
+            path.into_owned()
+        }
+    }
+
+    fn claim(&mut self, path: &Path, claim: Claim) {
+        self.claimed.insert(self.key(path), claim);
+    }
+
+    /// Claims `path` for the track, or the free path the policy resolves a collision to.
+    fn settle(&mut self, path: &Path, id: SpotifyId, policy: CollisionPolicy) -> Result<Settled> {
+        let owner = self
+            .claimed
+            .get(&self.key(path))
+            .filter(|claim| !claim.allows(id))
+            .map(|claim| claim.id);
+
+        let Some(owner) = owner else {
+            self.claim(path, Claim { id, in_job: true });
+            return Ok(Settled::Free);
+        };
+
+        let owner_uri = owner.to_uri().unwrap_or_default();
+
+        // A track which shows up twice in the job is only downloaded once
+        let policy = if owner == id { CollisionPolicy::Skip } else { policy };
+
+        tracing::warn!("{:?} is claimed by {}, resolving with {:?}", path, owner_uri, policy);
+
+        let resolved = match policy {
+            CollisionPolicy::NumericSuffix => (2..)
+                .map(|n| with_file_name_suffix(path, &format!(" ({})", n)))
+                .find(|path| {
+                    self.claimed
+                        .get(&self.key(path))
+                        .is_none_or(|claim| claim.allows(id))
+                }),
+            CollisionPolicy::AppendTrackId => Some(with_file_name_suffix(path, &format!(" - {}", id.to_base62()?))),
+            CollisionPolicy::Skip => None,
+            CollisionPolicy::Error => {
+                return Err(anyhow::anyhow!(
+                    "{} and {} would both be written to {:?}",
+                    owner_uri,
+                    id.to_uri()?,
+                    path
+                ));
+            }
+        };
+
+        let collision = Collision {
+            with: owner_uri,
+            path: path.to_path_buf(),
+            policy,
+        };
+
+        Ok(match resolved {
+            Some(resolved) => {
+                self.claim(&resolved, Claim { id, in_job: true });
+                Settled::Moved(resolved, collision)
+            }
+            None => Settled::Skipped(collision),
+        })
+    }
+}
+
+/// Adds the suffix to the file name, in front of the extension.
+fn with_file_name_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}{}.{}", stem, suffix, extension.to_string_lossy()),
+        None => format!("{}{}", stem, suffix),
+    };
+
+    path.with_file_name(file_name)
+}
+
+/// What a dry run found a track would go through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    Download,
+    /// Filtered out because its path is taken, see the collision.
+    Skip,
+    /// Its metadata or audio files couldn't be found.
+    Unavailable,
+}
+
+/// A track of a dry run.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanEntry {
+    /// The spotify uri of the track.
+    pub track: String,
+    pub action: PlanAction,
+    pub title: Option<String>,
+    pub path: Option<PathBuf>,
+    /// The paths of the extra formats.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_paths: Vec<PathBuf>,
+    /// Whether a file is already at the path, which is replaced unless the track is skipped.
+    pub exists: bool,
+    pub estimated_bytes: u64,
+    /// The quality which would be fetched, after falling back from the preferred one.
+    pub source_quality: Option<SourceQuality>,
+    pub collision: Option<Collision>,
+    /// Why the track is unavailable.
+    pub error: Option<String>,
+}
+
+impl PlanEntry {
+    pub fn unavailable(track: &Track, error: &anyhow::Error) -> Self {
+        PlanEntry {
+            track: track.id.to_uri().unwrap_or_default(),
+            action: PlanAction::Unavailable,
+            title: None,
+            path: None,
+            extra_paths: Vec::new(),
+            exists: false,
+            estimated_bytes: 0,
+            source_quality: None,
+            collision: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// The result of a dry run: what a job would do, without any audio being fetched.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobPlan {
+    pub tracks: Vec<PlanEntry>,
+    pub space: SpaceEstimate,
+}
+
+impl JobPlan {
+    pub fn with_action(&self, action: PlanAction) -> impl Iterator<Item = &PlanEntry> {
+        self.tracks.iter().filter(move |entry| entry.action == action)
+    }
+}
+
+impl fmt::Display for JobPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} to download ({} replacing existing files), {} skipped, {} unavailable",
+            self.with_action(PlanAction::Download).count(),
+            self.with_action(PlanAction::Download).filter(|entry| entry.exists).count(),
+            self.with_action(PlanAction::Skip).count(),
+            self.with_action(PlanAction::Unavailable).count()
+        )?;
+        writeln!(f, "{}", self.space)?;
+
+        for entry in &self.tracks {
+            match entry.action {
+                PlanAction::Download => {
+                    write!(f, "+ ")?;
+                    if let Some(path) = &entry.path {
+                        write!(f, "{}", path.display())?;
+                    }
+                    write!(f, " (~{} KiB", entry.estimated_bytes / 1024)?;
+                    if let Some(quality) = entry.source_quality {
+                        write!(f, ", {}", quality)?;
+                    }
+                    if entry.exists {
+                        write!(f, ", exists")?;
+                    }
+                    writeln!(f, ")")?;
+                    for path in &entry.extra_paths {
+                        writeln!(f, "  + {}", path.display())?;
+                    }
+                }
+                PlanAction::Skip => {
+                    let with = entry.collision.as_ref().map(|collision| collision.with.as_str()).unwrap_or_default();
+                    writeln!(f, "= {} (path taken by {})", entry.track, with)?;
+                }
+                PlanAction::Unavailable => {
+                    writeln!(f, "! {} ({})", entry.track, entry.error.as_deref().unwrap_or_default())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use librespot::core::spotify_id::SpotifyAudioType;
+
+    use super::*;
+    use crate::encoder::{EncoderSettings, Format};
+    use crate::manifest::ManifestEntry;
+    use crate::storage::MemoryStorage;
+    use crate::track::AlbumMetadata;
+
+    /// Tracks by their number with the path they were planned to.
+    type Tracks<'a> = Vec<(u128, &'a str)>;
+    /// The resolved path of every track, with the policy its collision was settled with.
+    type Resolved = Vec<(String, Option<CollisionPolicy>)>;
+
+    fn id(n: u128) -> SpotifyId {
+        SpotifyId {
+            id: n,
+            audio_type: SpotifyAudioType::Track,
+        }
+    }
+
+    fn track(n: u128, path: &str) -> PlannedTrack {
+        let metadata = TrackMetadata {
+            artists: Vec::new(),
+            track_name: format!("Track {}", n),
+            album: AlbumMetadata {
+                id: None,
+                name: "Album".to_string(),
+                artists: Vec::new(),
+                year: None,
+                cover: None,
+            },
+            duration: 0,
+            number: None,
+            disc_number: None,
+        };
+        PlannedTrack::new(Track::from_id(id(n)), metadata, PathBuf::from(path))
+    }
+
+    async fn manifest(files: &[(u128, &str)]) -> Manifest {
+        let mut manifest = Manifest::load(Arc::new(MemoryStorage::new()), "manifest.json").await.unwrap();
+        for (n, path) in files {
+            let settings = EncoderSettings::new(Format::Flac, None);
+            let entry = ManifestEntry::new(PathBuf::from(path), Format::Flac, settings, SourceQuality::default(), b"", None);
+            manifest.insert(&id(*n), entry).unwrap();
+        }
+        manifest
+    }
+
+    fn resolve(tracks: &[(u128, &str)], policy: CollisionPolicy, profile: SanitizeProfile, manifest: &Manifest) -> Result<Resolved> {
+        let mut planned: Vec<PlannedTrack> = tracks.iter().map(|(n, path)| track(*n, path)).collect();
+        resolve_collisions(&mut planned, policy, profile, manifest)?;
+
+        Ok(planned
+            .iter()
+            .map(|planned| {
+                (
+                    planned.path.to_string_lossy().into_owned(),
+                    planned.collision.as_ref().map(|collision| collision.policy),
+                )
+            })
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn settles_collisions_within_a_job() {
+        use CollisionPolicy::*;
+
+        let suffix = id(2).to_base62().unwrap();
+        let cases: Vec<(CollisionPolicy, SanitizeProfile, Tracks, Resolved)> = vec![
+            (
+                NumericSuffix,
+                SanitizeProfile::Posix,
+                vec![(1, "a/x.flac"), (2, "a/y.flac")],
+                vec![("a/x.flac".into(), None), ("a/y.flac".into(), None)],
+            ),
+            (
+                NumericSuffix,
+                SanitizeProfile::Posix,
+                vec![(1, "x.flac"), (2, "x.flac"), (3, "x.flac")],
+                vec![
+                    ("x.flac".into(), None),
+                    ("x (2).flac".into(), Some(NumericSuffix)),
+                    ("x (3).flac".into(), Some(NumericSuffix)),
+                ],
+            ),
+            // A suffixed path which is taken already is passed over
+            (
+                NumericSuffix,
+                SanitizeProfile::Posix,
+                vec![(1, "x (2).flac"), (2, "x.flac"), (3, "x.flac")],
+                vec![
+                    ("x (2).flac".into(), None),
+                    ("x.flac".into(), None),
+                    ("x (3).flac".into(), Some(NumericSuffix)),
+                ],
+            ),
+            // Only file systems which ignore case see these as the same
+            (
+                NumericSuffix,
+                SanitizeProfile::Posix,
+                vec![(1, "X.flac"), (2, "x.flac")],
+                vec![("X.flac".into(), None), ("x.flac".into(), None)],
+            ),
+            (
+                NumericSuffix,
+                SanitizeProfile::Ntfs,
+                vec![(1, "X.flac"), (2, "x.flac")],
+                vec![("X.flac".into(), None), ("x (2).flac".into(), Some(NumericSuffix))],
+            ),
+            (
+                AppendTrackId,
+                SanitizeProfile::Posix,
+                vec![(1, "x.flac"), (2, "x.flac")],
+                vec![("x.flac".into(), None), (format!("x - {}.flac", suffix), Some(AppendTrackId))],
+            ),
+            (
+                Skip,
+                SanitizeProfile::Posix,
+                vec![(1, "x.flac"), (2, "x.flac")],
+                vec![("x.flac".into(), None), ("x.flac".into(), Some(Skip))],
+            ),
+            // The same track twice is only downloaded once, whatever the policy
+            (
+                NumericSuffix,
+                SanitizeProfile::Posix,
+                vec![(1, "x.flac"), (1, "x.flac")],
+                vec![("x.flac".into(), None), ("x.flac".into(), Some(Skip))],
+            ),
+        ];
+
+        let manifest = manifest(&[]).await;
+        for (policy, profile, tracks, expected) in cases {
+            assert_eq!(resolve(&tracks, policy, profile, &manifest).unwrap(), expected, "{:?} {:?}", policy, tracks);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_collisions_with_the_error_policy() {
+        let manifest = manifest(&[]).await;
+        let tracks = [(1, "x.flac"), (2, "x.flac")];
+
+        assert!(resolve(&tracks, CollisionPolicy::Error, SanitizeProfile::Posix, &manifest).is_err());
+        // The same track twice isn't an error
+        let tracks = [(1, "x.flac"), (1, "x.flac")];
+        assert!(resolve(&tracks, CollisionPolicy::Error, SanitizeProfile::Posix, &manifest).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reports_the_track_which_claimed_the_path() {
+        let mut planned = vec![track(1, "x.flac"), track(2, "x.flac")];
+        resolve_collisions(&mut planned, CollisionPolicy::NumericSuffix, SanitizeProfile::Posix, &manifest(&[]).await).unwrap();
+
+        let collision = planned[1].collision.as_ref().unwrap();
+        assert_eq!(collision.with, id(1).to_uri().unwrap());
+        assert_eq!(collision.path, PathBuf::from("x.flac"));
+        assert!(!planned[1].is_duplicate());
+    }
+
+    #[tokio::test]
+    async fn keeps_numbering_stable_across_runs() {
+        let first = [(1, "x.flac"), (2, "x.flac")];
+        let resolved = resolve(&first, CollisionPolicy::NumericSuffix, SanitizeProfile::Posix, &manifest(&[]).await).unwrap();
+        assert_eq!(resolved[1].0, "x (2).flac");
+
+        // The files of the first run are in the manifest now
+        let manifest = manifest(&[(1, "x.flac"), (2, "x (2).flac")]).await;
+        let cases: Vec<(Tracks, Vec<&str>)> = vec![
+            (vec![(1, "x.flac"), (2, "x.flac")], vec!["x.flac", "x (2).flac"]),
+            // Without the first track in the job its file still claims the path
+            (vec![(2, "x.flac")], vec!["x (2).flac"]),
+            // The same track downloaded again replaces its own file
+            (vec![(1, "x.flac")], vec!["x.flac"]),
+            (vec![(3, "x.flac")], vec!["x (3).flac"]),
+            (vec![(3, "x.flac"), (2, "x.flac")], vec!["x (3).flac", "x (2).flac"]),
+        ];
+
+        for (tracks, expected) in cases {
+            let resolved = resolve(&tracks, CollisionPolicy::NumericSuffix, SanitizeProfile::Posix, &manifest).unwrap();
+            let paths: Vec<&str> = resolved.iter().map(|(path, _)| path.as_str()).collect();
+            assert_eq!(paths, expected, "{:?}", tracks);
+        }
+    }
+
+    #[tokio::test]
+    async fn moves_extra_files_along_with_the_main_file() {
+        let mut planned = vec![
+            track(1, "x.flac").with_extra_paths(vec![PathBuf::from("x.mp3")]),
+            track(2, "x.flac").with_extra_paths(vec![PathBuf::from("x.mp3"), PathBuf::from("mp3/x.mp3")]),
+        ];
+        resolve_collisions(&mut planned, CollisionPolicy::NumericSuffix, SanitizeProfile::Posix, &manifest(&[]).await).unwrap();
+
+        assert_eq!(planned[1].path, PathBuf::from("x (2).flac"));
+        assert_eq!(planned[1].extra_paths, vec![PathBuf::from("x (2).mp3"), PathBuf::from("mp3/x.mp3")]);
+
+        // An extra file in a folder of its own collides on its own
+        let mut planned = vec![
+            track(1, "a.flac").with_extra_paths(vec![PathBuf::from("mp3/x.mp3")]),
+            track(2, "b.flac").with_extra_paths(vec![PathBuf::from("mp3/x.mp3")]),
+        ];
+        resolve_collisions(&mut planned, CollisionPolicy::NumericSuffix, SanitizeProfile::Posix, &manifest(&[]).await).unwrap();
+        assert_eq!(planned[1].path, PathBuf::from("b.flac"));
+        assert_eq!(planned[1].extra_paths, vec![PathBuf::from("mp3/x (2).mp3")]);
+
+        // A track isn't written in only some of its formats
+        let mut planned = vec![
+            track(1, "a.flac").with_extra_paths(vec![PathBuf::from("mp3/x.mp3")]),
+            track(2, "b.flac").with_extra_paths(vec![PathBuf::from("mp3/x.mp3")]),
+        ];
+        resolve_collisions(&mut planned, CollisionPolicy::Skip, SanitizeProfile::Posix, &manifest(&[]).await).unwrap();
+        assert!(planned[1].is_skipped());
+    }
+}
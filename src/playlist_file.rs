@@ -0,0 +1,193 @@
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::plan::PlannedTrack;
+use crate::sanitize::Sanitizer;
+use crate::storage::Storage;
+use crate::track::TrackMetadata;
+
+/// Which playlist files are written next to every downloaded playlist and album.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlaylistFiles {
+    pub m3u8: bool,
+    pub xspf: bool,
+}
+
+impl Default for PlaylistFiles {
+    fn default() -> Self {
+        PlaylistFiles {
+            m3u8: true,
+            xspf: false,
+        }
+    }
+}
+
+struct Entry<'a> {
+    /// Relative to the playlist file, with `/` as separator.
+    location: String,
+    metadata: &'a TrackMetadata,
+}
+
+/// Writes the playlist files of a collection into the folder shared by its tracks, in the
+/// order of the collection. Tracks which weren't downloaded are left out.
+///
+/// Returns the path and content of every playlist file, whether it changed or not.
+pub async fn write(
+    name: &str,
+    tracks: &[&PlannedTrack],
+    destination: &Path,
+    files: PlaylistFiles,
+    sanitizer: &Sanitizer,
+    storage: &dyn Storage,
+) -> Result<Vec<(PathBuf, String)>> {
+    let mut written = Vec::with_capacity(tracks.len());
+    for planned in tracks.iter().copied() {
+        if (!planned.is_skipped() || planned.is_duplicate()) && storage.exists(&planned.path).await? {
+            written.push(planned);
+        }
+    }
+    let mut tracks = written;
+    if tracks.is_empty() || !(files.m3u8 || files.xspf) {
+        return Ok(Vec::new());
+    }
+    tracks.sort_by_key(|planned| planned.track.position);
+
+    let folder = common_folder(tracks.iter().map(|planned| planned.path.as_path()))
+        .unwrap_or_else(|| destination.to_path_buf());
+
+    let entries: Vec<Entry> = tracks
+        .iter()
+        .map(|planned| Entry {
+            location: relative_location(&planned.path, &folder),
+            metadata: &planned.metadata,
+        })
+        .collect();
+
+    let mut playlist_files = Vec::new();
+    if files.m3u8 {
+        let path = folder.join(format!("{}.m3u8", sanitizer.clean_reserving(name, 5)));
+        playlist_files.push((path, m3u8(name, &entries)));
+    }
+    if files.xspf {
+        let path = folder.join(format!("{}.xspf", sanitizer.clean_reserving(name, 5)));
+        playlist_files.push((path, xspf(name, &entries)));
+    }
+    for (path, content) in &playlist_files {
+        write_if_changed(storage, path, content).await?;
+    }
+
+    Ok(playlist_files)
+}
+
+async fn write_if_changed(storage: &dyn Storage, path: &Path, content: &str) -> Result<()> {
+    if storage.read(path).await?.as_deref() == Some(content.as_bytes()) {
+        return Ok(());
+    }
+
+    tracing::info!("Writing playlist file: {:?}", path);
+    storage.write(path, content.as_bytes()).await
+}
+
+fn m3u8(name: &str, entries: &[Entry]) -> String {
+    let mut content = String::from("#EXTM3U\n");
+    writeln!(content, "#PLAYLIST:{}", single_line(name)).unwrap();
+
+    for entry in entries {
+        writeln!(
+            content,
+            "#EXTINF:{},{} - {}",
+            (entry.metadata.duration + 500) / 1000,
+            single_line(&artists(entry.metadata)),
+            single_line(&entry.metadata.track_name)
+        )
+        .unwrap();
+        writeln!(content, "{}", entry.location).unwrap();
+    }
+
+    content
+}
+
+fn xspf(name: &str, entries: &[Entry]) -> String {
+    let mut content = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    content.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    writeln!(content, "  <title>{}</title>", escape_xml(name)).unwrap();
+    content.push_str("  <trackList>\n");
+
+    for entry in entries {
+        content.push_str("    <track>\n");
+        writeln!(content, "      <location>{}</location>", escape_xml(&encode_uri_path(&entry.location))).unwrap();
+        writeln!(content, "      <title>{}</title>", escape_xml(&entry.metadata.track_name)).unwrap();
+        writeln!(content, "      <creator>{}</creator>", escape_xml(&artists(entry.metadata))).unwrap();
+        writeln!(content, "      <album>{}</album>", escape_xml(&entry.metadata.album.name)).unwrap();
+        if let Some(number) = entry.metadata.number {
+            writeln!(content, "      <trackNum>{}</trackNum>", number).unwrap();
+        }
+        writeln!(content, "      <duration>{}</duration>", entry.metadata.duration).unwrap();
+        content.push_str("    </track>\n");
+    }
+
+    content.push_str("  </trackList>\n</playlist>\n");
+    content
+}
+
+fn artists(metadata: &TrackMetadata) -> String {
+    metadata
+        .artists
+        .iter()
+        .map(|artist| artist.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The deepest folder which contains all the paths.
+fn common_folder<'a>(mut paths: impl Iterator<Item = &'a Path>) -> Option<PathBuf> {
+    let mut folder = paths.next()?.parent()?.to_path_buf();
+    for path in paths {
+        while !path.starts_with(&folder) {
+            folder = folder.parent()?.to_path_buf();
+        }
+    }
+    Some(folder)
+}
+
+fn relative_location(path: &Path, folder: &Path) -> String {
+    path.strip_prefix(folder)
+        .unwrap_or(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Line breaks would end the `#EXTINF` line early.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Percent encodes everything except unreserved characters and the `/` separators,
+/// XSPF locations are URIs.
+fn encode_uri_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(byte as char),
+            _ => write!(encoded, "%{:02X}", byte).unwrap(),
+        }
+    }
+    encoded
+}
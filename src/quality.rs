@@ -0,0 +1,104 @@
+use std::fmt;
+
+use anyhow::Result;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{FileId, SpotifyId};
+use librespot::metadata::{AudioItem, FileFormat};
+use librespot::playback::config::Bitrate;
+use serde::{Deserialize, Serialize};
+
+/// The bitrate of the Ogg Vorbis stream fetched from spotify. 320 kbps needs a premium account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SourceQuality {
+    #[serde(rename = "96")]
+    Kbps96,
+    #[default]
+    #[serde(rename = "160")]
+    Kbps160,
+    #[serde(rename = "320")]
+    Kbps320,
+}
+
+impl SourceQuality {
+    pub fn kbps(&self) -> u32 {
+        match self {
+            SourceQuality::Kbps96 => 96,
+            SourceQuality::Kbps160 => 160,
+            SourceQuality::Kbps320 => 320,
+        }
+    }
+
+    pub(crate) fn bitrate(&self) -> Bitrate {
+        match self {
+            SourceQuality::Kbps96 => Bitrate::Bitrate96,
+            SourceQuality::Kbps160 => Bitrate::Bitrate160,
+            SourceQuality::Kbps320 => Bitrate::Bitrate320,
+        }
+    }
+
+    fn file_format(&self) -> FileFormat {
+        match self {
+            SourceQuality::Kbps96 => FileFormat::OGG_VORBIS_96,
+            SourceQuality::Kbps160 => FileFormat::OGG_VORBIS_160,
+            SourceQuality::Kbps320 => FileFormat::OGG_VORBIS_320,
+        }
+    }
+
+    /// The qualities tried in order when this one is preferred, the same order the player uses.
+    fn fallbacks(&self) -> [SourceQuality; 3] {
+        match self {
+            SourceQuality::Kbps96 => [SourceQuality::Kbps96, SourceQuality::Kbps160, SourceQuality::Kbps320],
+            SourceQuality::Kbps160 => [SourceQuality::Kbps160, SourceQuality::Kbps96, SourceQuality::Kbps320],
+            SourceQuality::Kbps320 => [SourceQuality::Kbps320, SourceQuality::Kbps160, SourceQuality::Kbps96],
+        }
+    }
+}
+
+impl fmt::Display for SourceQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} kbps", self.kbps())
+    }
+}
+
+/// Finds the quality the player will actually fetch for the track when `preferred` is asked for,
+/// following the track's alternatives when it isn't available itself.
+pub async fn resolve(session: &Session, id: SpotifyId, preferred: SourceQuality) -> Result<SourceQuality> {
+    let (quality, _, _) = resolve_file(session, id, preferred).await?;
+    Ok(quality)
+}
+
+/// Like `resolve`, also returning the id of the track or alternative which is available and
+/// the id of its file in that quality.
+pub async fn resolve_file(
+    session: &Session,
+    id: SpotifyId,
+    preferred: SourceQuality,
+) -> Result<(SourceQuality, SpotifyId, FileId)> {
+    let mut audio = AudioItem::get_audio_item(session, id)
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to get the audio files of the track"))?;
+
+    if !audio.available {
+        for alternative in audio.alternatives.clone().unwrap_or_default() {
+            let alternative = AudioItem::get_audio_item(session, alternative)
+                .await
+                .map_err(|_| anyhow::anyhow!("Failed to get the audio files of the track"))?;
+            if alternative.available {
+                audio = alternative;
+                break;
+            }
+        }
+    }
+
+    let (quality, file_id) = preferred
+        .fallbacks()
+        .into_iter()
+        .find_map(|quality| audio.files.get(&quality.file_format()).map(|file_id| (quality, *file_id)))
+        .ok_or(anyhow::anyhow!("{} is not available in any supported quality", audio.name))?;
+
+    if quality != preferred {
+        tracing::warn!("{} is not available in {}, falling back to {}", audio.name, preferred, quality);
+    }
+
+    Ok((quality, audio.id, file_id))
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use librespot::core::session::Session;
+use serde::Serialize;
+use tokio::sync::{watch, Notify};
+
+use crate::download::{DownloadOptions, Downloader};
+use crate::job::{JobHandle, JobState};
+use crate::manifest::Manifest;
+use crate::pacing::Pacer;
+use crate::report::{JobReport, TrackStatus};
+use crate::storage::Storage;
+use crate::track::{get_tracks, Track};
+use crate::DownloadState;
+
+/// The number of tracks downloaded at once over all jobs of a new queue.
+const DEFAULT_MAX_PARALLEL: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct JobId(u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job-{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuedJobState {
+    /// Waiting for its first track to get a slot.
+    Queued,
+    Running,
+    Paused,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+impl QueuedJobState {
+    pub fn is_done(&self) -> bool {
+        matches!(self, QueuedJobState::Finished | QueuedJobState::Cancelled | QueuedJobState::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuedTrackState {
+    Queued,
+    Running,
+    Downloaded,
+    Cancelled,
+    Skipped,
+    Failed,
+}
+
+impl From<&TrackStatus> for QueuedTrackState {
+    fn from(status: &TrackStatus) -> Self {
+        match status {
+            TrackStatus::Downloaded => QueuedTrackState::Downloaded,
+            TrackStatus::Cancelled => QueuedTrackState::Cancelled,
+            TrackStatus::Skipped => QueuedTrackState::Skipped,
+            TrackStatus::Failed { .. } => QueuedTrackState::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedTrack {
+    /// The spotify uri of the track.
+    pub track: String,
+    pub state: QueuedTrackState,
+}
+
+/// A snapshot of a job in the queue.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedJob {
+    pub id: JobId,
+    /// Jobs with a higher priority get free slots first.
+    pub priority: i32,
+    pub state: QueuedJobState,
+    /// Empty until the tracks of the job are resolved.
+    pub tracks: Vec<QueuedTrack>,
+    pub error: Option<String>,
+}
+
+struct Entry {
+    job: QueuedJob,
+    handle: JobHandle,
+    report: Option<JobReport>,
+    done: watch::Sender<bool>,
+}
+
+impl Entry {
+    /// The state of the job while it's running, taking pauses and cancellation into account.
+    fn state(&self) -> QueuedJobState {
+        match (self.job.state, self.handle.state()) {
+            (state, _) if state.is_done() => state,
+            (_, JobState::Cancelled) => QueuedJobState::Cancelled,
+            (_, JobState::Paused) => QueuedJobState::Paused,
+            (state, JobState::Running) => state,
+        }
+    }
+
+    fn snapshot(&self) -> QueuedJob {
+        QueuedJob {
+            state: self.state(),
+            ..self.job.clone()
+        }
+    }
+}
+
+struct Waiter {
+    job: JobId,
+    ticket: u64,
+}
+
+struct State {
+    max_parallel: usize,
+    running: usize,
+    next_id: u64,
+    next_ticket: u64,
+    /// The jobs in the order they are served among jobs of the same priority.
+    order: Vec<JobId>,
+    jobs: HashMap<JobId, Entry>,
+    waiting: Vec<Waiter>,
+}
+
+impl State {
+    /// The waiter which gets the next free slot: the highest priority first, then the job
+    /// closest to the front of the queue, then the unit which started waiting first. Jobs
+    /// which weren't submitted to the queue count as priority 0 behind the queued ones.
+    fn next_waiter(&self) -> Option<&Waiter> {
+        self.waiting.iter().min_by_key(|waiter| {
+            let priority = self.jobs.get(&waiter.job).map_or(0, |entry| entry.job.priority);
+            let position = self.order.iter().position(|id| *id == waiter.job).unwrap_or(usize::MAX);
+            (std::cmp::Reverse(priority), position, waiter.ticket)
+        })
+    }
+}
+
+struct Inner {
+    session: Session,
+    state: Arc<tokio::sync::Mutex<DownloadState>>,
+    manifest: Arc<tokio::sync::Mutex<Manifest>>,
+    pacer: Arc<Pacer>,
+    storage: Arc<dyn Storage>,
+    queue: Mutex<State>,
+    /// Wakes the waiting units whenever a slot frees up or the order changes.
+    changed: Notify,
+}
+
+/// A long-lived queue of download jobs sharing one limit on the tracks downloaded at once.
+///
+/// Jobs can be submitted at any time and run side by side, their tracks take the free slots
+/// by priority and position in the queue. Jobs started directly take slots as well. `DownloadOptions::parallel` still limits each job.
+#[derive(Clone)]
+pub struct DownloadQueue {
+    inner: Arc<Inner>,
+}
+
+impl DownloadQueue {
+    pub(crate) fn new(
+        session: Session,
+        state: Arc<tokio::sync::Mutex<DownloadState>>,
+        manifest: Arc<tokio::sync::Mutex<Manifest>>,
+        pacer: Arc<Pacer>,
+        storage: Arc<dyn Storage>,
+    ) -> Self {
+        DownloadQueue {
+            inner: Arc::new(Inner {
+                session,
+                state,
+                manifest,
+                pacer,
+                storage,
+                queue: Mutex::new(State {
+                    max_parallel: DEFAULT_MAX_PARALLEL,
+                    running: 0,
+                    next_id: 1,
+                    next_ticket: 0,
+                    order: Vec::new(),
+                    jobs: HashMap::new(),
+                    waiting: Vec::new(),
+                }),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Sets the number of tracks downloaded at once over all jobs. Running tracks above a
+    /// lowered limit finish, no new ones start until the queue is below it.
+    pub fn set_max_parallel(&self, max_parallel: usize) {
+        self.inner.queue.lock().unwrap().max_parallel = max_parallel.max(1);
+        self.inner.changed.notify_waiters();
+    }
+
+    /// A slot for a job which is started directly instead of being submitted, so its tracks
+    /// count against the same limit. The job doesn't show up in `jobs`.
+    pub(crate) fn slot(&self) -> QueueSlot {
+        let mut queue = self.inner.queue.lock().unwrap();
+        let job = JobId(queue.next_id);
+        queue.next_id += 1;
+
+        QueueSlot {
+            inner: Arc::clone(&self.inner),
+            job,
+        }
+    }
+
+    /// Adds a job to the back of the queue and starts it in the background.
+    ///
+    /// The job is controlled through the `JobHandle` in the options, like any other job, and is
+    /// cancelled by `SpotifyDownloader::shutdown`.
+    pub fn submit(&self, track_url: Vec<String>, options: DownloadOptions, priority: i32) -> JobId {
+        let id = {
+            let mut queue = self.inner.queue.lock().unwrap();
+            let id = JobId(queue.next_id);
+            queue.next_id += 1;
+            queue.order.push(id);
+            queue.jobs.insert(
+                id,
+                Entry {
+                    job: QueuedJob {
+                        id,
+                        priority,
+                        state: QueuedJobState::Queued,
+                        tracks: Vec::new(),
+                        error: None,
+                    },
+                    handle: options.job.clone(),
+                    report: None,
+                    done: watch::channel(false).0,
+                },
+            );
+            id
+        };
+
+        let inner = Arc::clone(&self.inner);
+        tokio::spawn(async move {
+            let shutdown_listener = crate::cancel_on_shutdown(&inner.state, options.job.clone()).await;
+
+            let report = async {
+                let tracks = get_tracks(track_url, &inner.session).await?;
+                let downloader = Downloader::new(
+                    &inner.session,
+                    Arc::clone(&inner.state),
+                    Arc::clone(&inner.manifest),
+                    Arc::clone(&inner.pacer),
+                    Arc::clone(&inner.storage),
+                )
+                .in_queue(QueueSlot {
+                    inner: Arc::clone(&inner),
+                    job: id,
+                });
+                downloader.download_tracks(tracks, &options).await
+            }
+            .await;
+
+            shutdown_listener.abort();
+
+            let mut queue = inner.queue.lock().unwrap();
+            if let Some(entry) = queue.jobs.get_mut(&id) {
+                match report {
+                    Ok(report) => {
+                        entry.job.state = if report.cancelled() {
+                            QueuedJobState::Cancelled
+                        } else {
+                            QueuedJobState::Finished
+                        };
+                        entry.report = Some(report);
+                    }
+                    Err(e) => {
+                        tracing::error!("Queued job {} failed: {:?}", id, e);
+                        entry.job.state = QueuedJobState::Failed;
+                        entry.job.error = Some(e.to_string());
+                    }
+                }
+                entry.done.send_replace(true);
+            }
+            queue.order.retain(|queued| *queued != id);
+        });
+
+        id
+    }
+
+    pub fn set_priority(&self, id: JobId, priority: i32) -> Result<()> {
+        let mut queue = self.inner.queue.lock().unwrap();
+        let entry = queue.jobs.get_mut(&id).ok_or(anyhow::anyhow!("Unknown job {}", id))?;
+        entry.job.priority = priority;
+        drop(queue);
+
+        self.inner.changed.notify_waiters();
+        Ok(())
+    }
+
+    /// Moves an unfinished job to `position` in the queue, 0 being the front. Jobs of a higher
+    /// priority are still served first.
+    pub fn move_job(&self, id: JobId, position: usize) -> Result<()> {
+        let mut queue = self.inner.queue.lock().unwrap();
+        let current = queue
+            .order
+            .iter()
+            .position(|queued| *queued == id)
+            .ok_or(anyhow::anyhow!("{} is not in the queue", id))?;
+        queue.order.remove(current);
+        let position = position.min(queue.order.len());
+        queue.order.insert(position, id);
+        drop(queue);
+
+        self.inner.changed.notify_waiters();
+        Ok(())
+    }
+
+    pub fn cancel(&self, id: JobId) -> Result<()> {
+        let handle = self.handle(id)?;
+        handle.cancel();
+        Ok(())
+    }
+
+    /// The handle of a job, to pause or resume it or to cancel single tracks.
+    pub fn handle(&self, id: JobId) -> Result<JobHandle> {
+        let queue = self.inner.queue.lock().unwrap();
+        let entry = queue.jobs.get(&id).ok_or(anyhow::anyhow!("Unknown job {}", id))?;
+        Ok(entry.handle.clone())
+    }
+
+    pub fn job(&self, id: JobId) -> Option<QueuedJob> {
+        let queue = self.inner.queue.lock().unwrap();
+        queue.jobs.get(&id).map(Entry::snapshot)
+    }
+
+    /// Every job submitted to the queue, the unfinished ones in queue order first.
+    pub fn jobs(&self) -> Vec<QueuedJob> {
+        let queue = self.inner.queue.lock().unwrap();
+        let mut jobs: Vec<QueuedJob> = queue
+            .order
+            .iter()
+            .filter_map(|id| queue.jobs.get(id))
+            .map(Entry::snapshot)
+            .collect();
+
+        let mut done: Vec<QueuedJob> = queue
+            .jobs
+            .values()
+            .filter(|entry| !queue.order.contains(&entry.job.id))
+            .map(Entry::snapshot)
+            .collect();
+        done.sort_by_key(|job| job.id);
+        jobs.extend(done);
+
+        jobs
+    }
+
+    /// Waits for the job to end and returns its report.
+    pub async fn wait(&self, id: JobId) -> Result<JobReport> {
+        let mut done = {
+            let queue = self.inner.queue.lock().unwrap();
+            let entry = queue.jobs.get(&id).ok_or(anyhow::anyhow!("Unknown job {}", id))?;
+            entry.done.subscribe()
+        };
+        done.wait_for(|done| *done).await?;
+
+        let queue = self.inner.queue.lock().unwrap();
+        let entry = queue.jobs.get(&id).ok_or(anyhow::anyhow!("Unknown job {}", id))?;
+        match (&entry.report, &entry.job.error) {
+            (Some(report), _) => Ok(report.clone()),
+            (None, error) => Err(anyhow::anyhow!("{} failed: {}", id, error.as_deref().unwrap_or_default())),
+        }
+    }
+
+    /// Forgets the finished jobs, returning how many were removed.
+    pub fn clear_finished(&self) -> usize {
+        let mut queue = self.inner.queue.lock().unwrap();
+        let before = queue.jobs.len();
+        queue.jobs.retain(|_, entry| !entry.job.state.is_done());
+        before - queue.jobs.len()
+    }
+}
+
+/// Ties the downloader of a queued job to the queue: its work units wait for a free slot
+/// before they start and report the state of their tracks.
+pub(crate) struct QueueSlot {
+    inner: Arc<Inner>,
+    job: JobId,
+}
+
+impl QueueSlot {
+    pub(crate) fn set_tracks(&self, tracks: &[Track]) {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if let Some(entry) = queue.jobs.get_mut(&self.job) {
+            entry.job.tracks = tracks
+                .iter()
+                .map(|track| QueuedTrack {
+                    track: track.id.to_uri().unwrap_or_default(),
+                    state: QueuedTrackState::Queued,
+                })
+                .collect();
+        }
+    }
+
+    pub(crate) fn set_track_state(&self, track: &Track, state: QueuedTrackState) {
+        let uri = track.id.to_uri().unwrap_or_default();
+        let mut queue = self.inner.queue.lock().unwrap();
+        if let Some(entry) = queue.jobs.get_mut(&self.job) {
+            if state == QueuedTrackState::Running && entry.job.state == QueuedJobState::Queued {
+                entry.job.state = QueuedJobState::Running;
+            }
+            if let Some(queued) = entry.job.tracks.iter_mut().find(|queued| queued.track == uri) {
+                queued.state = state;
+            }
+        }
+    }
+
+    /// Waits for a free slot in the queue.
+    pub(crate) async fn acquire(&self) -> Permit {
+        let ticket = {
+            let mut queue = self.inner.queue.lock().unwrap();
+            let ticket = queue.next_ticket;
+            queue.next_ticket += 1;
+            queue.waiting.push(Waiter { job: self.job, ticket });
+            ticket
+        };
+        let mut waiting = Waiting {
+            inner: &self.inner,
+            ticket: Some(ticket),
+        };
+
+        loop {
+            let changed = self.inner.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            {
+                let mut queue = self.inner.queue.lock().unwrap();
+                let next = queue.next_waiter().map(|waiter| waiter.ticket);
+                if queue.running < queue.max_parallel && next == Some(ticket) {
+                    queue.waiting.retain(|waiter| waiter.ticket != ticket);
+                    queue.running += 1;
+                    waiting.ticket = None;
+                    drop(queue);
+
+                    // The next waiter might fit as well
+                    self.inner.changed.notify_waiters();
+                    return Permit {
+                        inner: Arc::clone(&self.inner),
+                    };
+                }
+            }
+
+            changed.await;
+        }
+    }
+}
+
+/// Takes the waiter out of the queue when it stops waiting without getting a slot.
+struct Waiting<'a> {
+    inner: &'a Inner,
+    ticket: Option<u64>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            self.inner.queue.lock().unwrap().waiting.retain(|waiter| waiter.ticket != ticket);
+            self.inner.changed.notify_waiters();
+        }
+    }
+}
+
+/// A slot in the queue, freed when dropped.
+pub(crate) struct Permit {
+    inner: Arc<Inner>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.inner.queue.lock().unwrap().running -= 1;
+        self.inner.changed.notify_waiters();
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio_check::AudioIssue;
+use crate::plan::{Collision, JobPlan};
+use crate::track::Track;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackStatus {
+    Downloaded,
+    Cancelled,
+    /// Left out because another track of the job claimed the same output path, or because
+    /// it exists already with `DownloadOptions::skip_existing`.
+    Skipped,
+    /// Given up on, the rest of the job went on without it.
+    Failed { reason: String },
+}
+
+impl TrackStatus {
+    /// Whether the track needs no further work when the job is resumed.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, TrackStatus::Downloaded | TrackStatus::Skipped)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackReport {
+    /// The spotify uri of the track.
+    pub track: String,
+    pub path: Option<PathBuf>,
+    pub status: TrackStatus,
+    /// Set when the track's output path collided with another track's.
+    pub collision: Option<Collision>,
+    /// Problems found in the track's audio, which make it suspect.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub issues: Vec<AudioIssue>,
+    /// The files of the extra formats, written next to `path`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_paths: Vec<PathBuf>,
+}
+
+impl TrackReport {
+    pub fn new(track: &Track, path: Option<PathBuf>, status: TrackStatus) -> Self {
+        TrackReport {
+            track: track.id.to_uri().unwrap_or_default(),
+            path,
+            status,
+            collision: None,
+            issues: Vec::new(),
+            extra_paths: Vec::new(),
+        }
+    }
+
+    pub fn with_collision(mut self, collision: Option<Collision>) -> Self {
+        self.collision = collision;
+        self
+    }
+
+    pub fn with_issues(mut self, issues: Vec<AudioIssue>) -> Self {
+        self.issues = issues;
+        self
+    }
+
+    pub fn with_extra_paths(mut self, extra_paths: Vec<PathBuf>) -> Self {
+        self.extra_paths = extra_paths;
+        self
+    }
+
+    /// Whether the track was downloaded although its audio looks broken.
+    pub fn is_suspect(&self) -> bool {
+        !self.issues.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobReport {
+    pub tracks: Vec<TrackReport>,
+    /// Set instead of the tracks for dry runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plan: Option<JobPlan>,
+}
+
+impl JobReport {
+    pub fn with_status(&self, status: TrackStatus) -> impl Iterator<Item = &TrackReport> {
+        self.tracks.iter().filter(move |track| track.status == status)
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.with_status(TrackStatus::Cancelled).next().is_some()
+    }
+
+    /// The tracks which were given up on.
+    pub fn failed(&self) -> impl Iterator<Item = &TrackReport> {
+        self.tracks
+            .iter()
+            .filter(|track| matches!(track.status, TrackStatus::Failed { .. }))
+    }
+
+    /// The tracks whose output path collided with another track's.
+    pub fn collisions(&self) -> impl Iterator<Item = &TrackReport> {
+        self.tracks.iter().filter(|track| track.collision.is_some())
+    }
+
+    /// The tracks whose audio looked broken even after retrying them.
+    pub fn suspect(&self) -> impl Iterator<Item = &TrackReport> {
+        self.tracks.iter().filter(|track| track.is_suspect())
+    }
+}
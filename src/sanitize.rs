@@ -0,0 +1,286 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+/// The file system the output is written to, which decides the rules file names have to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SanitizeProfile {
+    Posix,
+    Ntfs,
+    /// FAT32 long file names. Many devices and drivers store them as UCS-2, so characters
+    /// outside the Basic Multilingual Plane are transliterated.
+    Fat32,
+    /// SMB/CIFS shares, which follow the Windows naming rules whatever the server's file system is.
+    Smb,
+}
+
+impl Default for SanitizeProfile {
+    fn default() -> Self {
+        if cfg!(windows) {
+            SanitizeProfile::Ntfs
+        } else {
+            SanitizeProfile::Posix
+        }
+    }
+}
+
+const WINDOWS_INVALID_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The longest name POSIX file systems allow, in bytes, and Windows ones, in UTF-16 code units.
+const MAX_NAME_LENGTH: usize = 255;
+
+impl SanitizeProfile {
+    fn is_invalid(&self, c: char) -> bool {
+        if c.is_control() {
+            return true;
+        }
+
+        match self {
+            SanitizeProfile::Posix => c == '/',
+            SanitizeProfile::Ntfs | SanitizeProfile::Fat32 | SanitizeProfile::Smb => {
+                WINDOWS_INVALID_CHARS.contains(&c)
+            }
+        }
+    }
+
+    fn is_representable(&self, c: char) -> bool {
+        match self {
+            SanitizeProfile::Fat32 => u32::from(c) <= 0xFFFF,
+            _ => true,
+        }
+    }
+
+    fn follows_windows_rules(&self) -> bool {
+        !matches!(self, SanitizeProfile::Posix)
+    }
+
+    /// The limits of a name: POSIX file systems count bytes, Windows ones UTF-16 code units.
+    fn max_length(&self) -> Limits {
+        if self.follows_windows_rules() {
+            Limits {
+                bytes: None,
+                utf16_units: Some(MAX_NAME_LENGTH),
+            }
+        } else {
+            Limits {
+                bytes: Some(MAX_NAME_LENGTH),
+                utf16_units: None,
+            }
+        }
+    }
+
+    /// Whether names which only differ in case refer to the same file.
+    pub(crate) fn is_case_insensitive(&self) -> bool {
+        self.follows_windows_rules()
+    }
+}
+
+/// Cleans file and folder names so they are valid on the target file system.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Sanitizer {
+    pub profile: SanitizeProfile,
+    /// Replaces non-ascii characters with their closest ascii representation.
+    pub transliterate: bool,
+    /// Maximum length of a single path component in bytes. Without it the limit of the
+    /// profile's file system applies: 255 bytes on POSIX, 255 UTF-16 code units otherwise.
+    pub max_bytes: Option<usize>,
+}
+
+impl Sanitizer {
+    pub fn new(profile: SanitizeProfile) -> Self {
+        Sanitizer {
+            profile,
+            ..Sanitizer::default()
+        }
+    }
+
+    /// Cleans a single path component.
+    pub fn clean(&self, name: &str) -> String {
+        self.clean_reserving(name, 0)
+    }
+
+    /// Cleans a single path component, keeping room for `reserved` ascii characters of a
+    /// suffix like the file extension.
+    pub fn clean_reserving(&self, name: &str, reserved: usize) -> String {
+        let normalized: String = name.nfc().collect();
+        let normalized = if self.transliterate {
+            deunicode::deunicode(&normalized)
+        } else {
+            normalized
+        };
+
+        let mut clean = String::with_capacity(normalized.len());
+        for c in normalized.chars().filter(|c| !self.profile.is_invalid(*c)) {
+            if self.profile.is_representable(c) {
+                clean.push(c);
+            } else {
+                clean.push_str(deunicode::deunicode_char(c).unwrap_or("_"));
+            }
+        }
+
+        let mut limits = self.profile.max_length();
+        if self.max_bytes.is_some() {
+            limits.bytes = self.max_bytes;
+        }
+        let limits = limits.reserving(reserved);
+        limits.truncate(&mut clean);
+
+        let mut clean = clean.trim_start().to_string();
+        if self.profile.follows_windows_rules() {
+            // Windows silently drops trailing dots and spaces, which makes the name unreachable
+            clean.truncate(clean.trim_end_matches(['.', ' ']).len());
+
+            let stem = clean.split('.').next().unwrap_or_default();
+            if WINDOWS_RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+                clean.insert(stem.len(), '_');
+                limits.truncate(&mut clean);
+            }
+        } else {
+            clean.truncate(clean.trim_end().len());
+        }
+
+        // A name can't be empty, and these refer to the folders themselves
+        if clean.is_empty() || clean == "." || clean == ".." {
+            return "_".to_string();
+        }
+
+        clean
+    }
+}
+
+/// The longest a name may be, in bytes of UTF-8 and in UTF-16 code units.
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    bytes: Option<usize>,
+    utf16_units: Option<usize>,
+}
+
+impl Limits {
+    fn reserving(self, reserved: usize) -> Self {
+        Limits {
+            bytes: self.bytes.map(|bytes| bytes.saturating_sub(reserved)),
+            utf16_units: self.utf16_units.map(|units| units.saturating_sub(reserved)),
+        }
+    }
+
+    /// Truncates the string to the limits without splitting a character.
+    fn truncate(&self, value: &mut String) {
+        let mut bytes = 0;
+        let mut units = 0;
+        for (index, c) in value.char_indices() {
+            bytes += c.len_utf8();
+            units += c.len_utf16();
+            if self.bytes.is_some_and(|max| bytes > max) || self.utf16_units.is_some_and(|max| units > max) {
+                value.truncate(index);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(profile: SanitizeProfile, name: &str) -> String {
+        Sanitizer::new(profile).clean(name)
+    }
+
+    #[test]
+    fn renames_reserved_names_on_windows() {
+        let cases = [
+            ("CON", "CON_"),
+            ("con", "con_"),
+            ("Nul.flac", "Nul_.flac"),
+            ("LPT9.tar.gz", "LPT9_.tar.gz"),
+            ("CONSOLE", "CONSOLE"),
+            ("COM10", "COM10"),
+        ];
+        for profile in [SanitizeProfile::Ntfs, SanitizeProfile::Fat32, SanitizeProfile::Smb] {
+            for (name, expected) in cases {
+                assert_eq!(clean(profile, name), expected, "{:?} {:?}", profile, name);
+            }
+        }
+        assert_eq!(clean(SanitizeProfile::Posix, "CON"), "CON");
+    }
+
+    #[test]
+    fn strips_trailing_dots_and_spaces() {
+        let cases = [
+            (SanitizeProfile::Ntfs, "Album. ", "Album"),
+            (SanitizeProfile::Ntfs, "  Album...", "Album"),
+            (SanitizeProfile::Smb, "Vol. 2.", "Vol. 2"),
+            (SanitizeProfile::Posix, "Album. ", "Album."),
+            (SanitizeProfile::Posix, "  Album ", "Album"),
+            (SanitizeProfile::Ntfs, "...", "_"),
+            (SanitizeProfile::Ntfs, "..", "_"),
+            (SanitizeProfile::Posix, " ", "_"),
+            (SanitizeProfile::Posix, "..", "_"),
+            (SanitizeProfile::Posix, ".", "_"),
+        ];
+        for (profile, name, expected) in cases {
+            assert_eq!(clean(profile, name), expected, "{:?} {:?}", profile, name);
+        }
+    }
+
+    #[test]
+    fn removes_invalid_characters() {
+        let cases = [
+            (SanitizeProfile::Posix, "AC/DC: Live?", "ACDC: Live?"),
+            (SanitizeProfile::Ntfs, "AC/DC: Live?", "ACDC Live"),
+            (SanitizeProfile::Fat32, "a<b>c|d\\e*\"f", "abcdef"),
+            (SanitizeProfile::Posix, "tab\there\n", "tabhere"),
+        ];
+        for (profile, name, expected) in cases {
+            assert_eq!(clean(profile, name), expected, "{:?} {:?}", profile, name);
+        }
+    }
+
+    #[test]
+    fn normalizes_and_transliterates() {
+        // "e" followed by a combining acute accent
+        assert_eq!(clean(SanitizeProfile::Posix, "Beyonce\u{301}"), "Beyonc\u{e9}");
+
+        let mut sanitizer = Sanitizer::new(SanitizeProfile::Posix);
+        sanitizer.transliterate = true;
+        assert_eq!(sanitizer.clean("Sigur Rós – Hoppípolla"), "Sigur Ros - Hoppipolla");
+    }
+
+    #[test]
+    fn transliterates_characters_outside_the_bmp_on_fat32() {
+        assert_eq!(clean(SanitizeProfile::Fat32, "Café 𝄞"), "Café G");
+        assert_eq!(clean(SanitizeProfile::Ntfs, "Café 𝄞"), "Café 𝄞");
+    }
+
+    #[test]
+    fn truncates_without_splitting_characters() {
+        let mut sanitizer = Sanitizer::new(SanitizeProfile::Posix);
+        sanitizer.max_bytes = Some(6);
+        let cases = [("abcdefgh", "abcdef"), ("ééééé", "ééé"), ("aéééé", "aéé"), ("日本語", "日本")];
+        for (name, expected) in cases {
+            assert_eq!(sanitizer.clean(name), expected, "{:?}", name);
+        }
+
+        // The reserved room counts against the limit as well
+        assert_eq!(sanitizer.clean_reserving("abcdefgh", 4), "ab");
+    }
+
+    #[test]
+    fn counts_the_limit_of_the_file_system() {
+        let long = "é".repeat(300);
+
+        // 255 bytes on POSIX, 255 UTF-16 code units on Windows
+        assert_eq!(clean(SanitizeProfile::Posix, &long), "é".repeat(127));
+        assert_eq!(clean(SanitizeProfile::Ntfs, &long), "é".repeat(255));
+        assert_eq!(Sanitizer::new(SanitizeProfile::Ntfs).clean_reserving(&long, 5), "é".repeat(250));
+
+        // Characters outside the BMP take two code units and are never split
+        let emoji = "😀".repeat(200);
+        assert_eq!(clean(SanitizeProfile::Smb, &emoji), "😀".repeat(127));
+    }
+}
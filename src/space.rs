@@ -0,0 +1,111 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::encoder::{EncoderSettings, Format};
+use crate::quality::SourceQuality;
+use crate::track::TrackMetadata;
+
+/// Bytes per second of the 16 bit stereo PCM the player decodes to.
+const PCM_BYTES_PER_SECOND: f64 = 44100.0 * 2.0 * 2.0;
+/// Flac of a lossy source usually ends up a bit above half the size of the PCM.
+const FLAC_RATIO: f64 = 0.6;
+/// The fixed predictors of the fastest FLAC compression levels compress a bit worse.
+const FLAC_FAST_RATIO: f64 = 0.65;
+/// Room for the tags and container overhead of every file.
+const FILE_OVERHEAD_BYTES: u64 = 16 * 1024;
+
+/// What happens when the estimated size of a job doesn't fit into the free space at the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpaceAction {
+    /// The job refuses to start.
+    #[default]
+    Refuse,
+    /// The job logs a warning and starts anyway.
+    Warn,
+    /// Free space isn't checked.
+    Ignore,
+}
+
+/// The pre-flight check of the free space at the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpaceCheck {
+    pub action: SpaceAction,
+    /// Bytes which have to stay free after the job, 100 MiB by default.
+    pub reserve_bytes: u64,
+}
+
+impl Default for SpaceCheck {
+    fn default() -> Self {
+        SpaceCheck {
+            action: SpaceAction::default(),
+            reserve_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+/// The estimated size of a job compared with the free space at its destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SpaceEstimate {
+    /// Tracks which will be written.
+    pub tracks: usize,
+    pub estimated_bytes: u64,
+    /// Unknown for storages which aren't the local file system.
+    pub available_bytes: Option<u64>,
+    pub reserve_bytes: u64,
+}
+
+impl SpaceEstimate {
+    /// Whether the job fits, which is assumed when the free space isn't known.
+    pub fn fits(&self) -> bool {
+        self.available_bytes
+            .is_none_or(|available| self.estimated_bytes.saturating_add(self.reserve_bytes) <= available)
+    }
+}
+
+impl fmt::Display for SpaceEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} tracks need about {} MiB", self.tracks, self.estimated_bytes / (1024 * 1024))?;
+        match self.available_bytes {
+            Some(available) => write!(
+                f,
+                ", {} MiB are available ({} MiB reserved)",
+                available / (1024 * 1024),
+                self.reserve_bytes / (1024 * 1024)
+            ),
+            None => write!(f, ", the free space is unknown"),
+        }
+    }
+}
+
+/// Estimates the size of the file a track is written to from its duration and the settings
+/// it's encoded with.
+pub fn estimate_track_size(metadata: &TrackMetadata, format: Format, settings: EncoderSettings, quality: SourceQuality) -> u64 {
+    let seconds = f64::from(metadata.duration.max(0)) / 1000.0;
+
+    let bitrate_kbps = settings.bitrate_kbps.or(EncoderSettings::new(format, None).bitrate_kbps);
+    let bytes_per_second = match bitrate_kbps {
+        Some(kbps) => f64::from(kbps) * 1000.0 / 8.0,
+        // Passed through as fetched
+        None if format == Format::Ogg => f64::from(quality.kbps()) * 1000.0 / 8.0,
+        None if settings.compression.is_some_and(|level| level <= 2) => PCM_BYTES_PER_SECOND * FLAC_FAST_RATIO,
+        None => PCM_BYTES_PER_SECOND * FLAC_RATIO,
+    };
+
+    (seconds * bytes_per_second) as u64 + FILE_OVERHEAD_BYTES
+}
+
+/// The free space of the file system the destination is on, looking at the closest existing
+/// parent if the destination doesn't exist yet.
+pub fn available_space(destination: &Path) -> Result<u64> {
+    let existing = destination
+        .ancestors()
+        .find(|path| path.exists())
+        .ok_or(anyhow::anyhow!("No existing parent of {:?}", destination))?;
+
+    Ok(fs2::available_space(existing)?)
+}
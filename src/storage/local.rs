@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::Storage;
+use crate::encoder::EncodedStream;
+
+/// Files on the local file system, written to a `.part` file first and renamed when complete.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalStorage;
+
+#[async_trait::async_trait]
+impl Storage for LocalStorage {
+    async fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let partial_path = EncodedStream::partial_path(path);
+        let result = async {
+            let mut file = tokio::fs::File::create(&partial_path).await?;
+            tokio::io::AsyncWriteExt::write_all(&mut file, content).await?;
+            file.sync_all().await?;
+            drop(file);
+            tokio::fs::rename(&partial_path, path).await
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+        }
+        Ok(result?)
+    }
+
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(tokio::fs::try_exists(path).await?)
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(from, to).await?;
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
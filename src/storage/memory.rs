@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use super::Storage;
+
+/// Keeps every file in memory, for tests and for callers which handle the files themselves.
+///
+/// Clones share their files.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<BTreeMap<PathBuf, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    /// The paths of every file, in order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for MemoryStorage {
+    async fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        self.files.lock().unwrap().insert(path.to_path_buf(), content.to_vec());
+        Ok(())
+    }
+
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(path))
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.files.lock().unwrap().contains_key(path))
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        self.files.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let content = files
+            .remove(from)
+            .ok_or(anyhow::anyhow!("{:?} doesn't exist", from))?;
+        files.insert(to.to_path_buf(), content);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_files_shared_by_clones() {
+        let storage = MemoryStorage::new();
+        let clone = storage.clone();
+        let path = Path::new("music/Artist/Track.flac");
+
+        assert!(!storage.exists(path).await.unwrap());
+        assert_eq!(storage.read(path).await.unwrap(), None);
+
+        clone.write(path, b"audio").await.unwrap();
+        assert!(storage.exists(path).await.unwrap());
+        assert_eq!(storage.read(path).await.unwrap(), Some(b"audio".to_vec()));
+
+        storage.write(path, b"replaced").await.unwrap();
+        assert_eq!(clone.get(path), Some(b"replaced".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn renames_and_removes_files() {
+        let storage = MemoryStorage::new();
+        let from = Path::new("music/a.flac");
+        let to = Path::new("music/b.flac");
+
+        storage.write(from, b"a").await.unwrap();
+        storage.write(Path::new("music/c.flac"), b"c").await.unwrap();
+        storage.rename(from, to).await.unwrap();
+        assert_eq!(storage.paths(), vec![PathBuf::from("music/b.flac"), PathBuf::from("music/c.flac")]);
+        assert!(storage.rename(from, to).await.is_err());
+
+        storage.remove(to).await.unwrap();
+        // Removing a missing file is fine
+        storage.remove(to).await.unwrap();
+        assert_eq!(storage.paths(), vec![PathBuf::from("music/c.flac")]);
+    }
+}
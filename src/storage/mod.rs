@@ -0,0 +1,136 @@
+mod local;
+mod memory;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "webdav")]
+mod webdav;
+
+use std::fmt::Debug;
+use std::path::Path;
+#[cfg(any(feature = "s3", feature = "webdav"))]
+use std::path::Component;
+
+use anyhow::Result;
+
+pub use self::local::LocalStorage;
+pub use self::memory::MemoryStorage;
+#[cfg(feature = "s3")]
+pub use self::s3::{S3Config, S3Storage};
+#[cfg(feature = "webdav")]
+pub use self::webdav::{WebDavConfig, WebDavStorage};
+
+/// Where the files of a downloader end up: the tracks, playlist files, cue sheets and the manifest.
+///
+/// Paths are the output paths computed from the destination and the path templates. Storages
+/// which aren't a file system use their components, joined with `/`, as keys.
+#[async_trait::async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Writes the file, replacing an existing one. Readers never see a partially written file.
+    async fn write(&self, path: &Path, content: &[u8]) -> Result<()>;
+
+    /// Reads the file, `None` if it doesn't exist.
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>>;
+
+    async fn exists(&self, path: &Path) -> Result<bool>;
+
+    /// Removes the file, doing nothing if it doesn't exist.
+    async fn remove(&self, path: &Path) -> Result<()>;
+
+    /// Moves the file to `to`, replacing what's there.
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Whether the paths are on the local file system, which makes the free space at the
+    /// destination meaningful.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// The key of a path in storages which aren't a file system, like `Music/Artist/Track.flac`.
+#[cfg(any(feature = "s3", feature = "webdav"))]
+pub(crate) fn key(path: &Path) -> Result<String> {
+    let mut segments = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(segment) => segments.push(
+                segment
+                    .to_str()
+                    .ok_or(anyhow::anyhow!("{:?} is not valid unicode", path))?,
+            ),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => return Err(anyhow::anyhow!("{:?} leaves the storage", path)),
+        }
+    }
+
+    if segments.is_empty() {
+        return Err(anyhow::anyhow!("{:?} is not a file", path));
+    }
+    Ok(segments.join("/"))
+}
+
+/// Percent-encodes every byte of a key segment except the unreserved characters of RFC 3986.
+#[cfg(any(feature = "s3", feature = "webdav"))]
+pub(crate) fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Encodes a key for use in a url path, keeping the `/` separators.
+#[cfg(any(feature = "s3", feature = "webdav"))]
+pub(crate) fn encode_key(key: &str) -> String {
+    key.split('/').map(encode_segment).collect::<Vec<_>>().join("/")
+}
+
+/// The year, month and day of a day counted from 1970-01-01.
+pub(crate) fn civil_date(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's days_from_civil, reversed
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_date_converts_days() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(15849), (2013, 5, 24));
+        assert_eq!(civil_date(19782), (2024, 2, 29));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+    }
+
+    #[cfg(any(feature = "s3", feature = "webdav"))]
+    #[test]
+    fn key_joins_normal_components() {
+        assert_eq!(key(Path::new("/music/Artist/Track.flac")).unwrap(), "music/Artist/Track.flac");
+        assert_eq!(key(Path::new("./music/./Track.flac")).unwrap(), "music/Track.flac");
+        assert!(key(Path::new("music/../Track.flac")).is_err());
+        assert!(key(Path::new("/")).is_err());
+    }
+
+    #[cfg(any(feature = "s3", feature = "webdav"))]
+    #[test]
+    fn encode_key_escapes_everything_but_unreserved() {
+        assert_eq!(encode_key("test$file.text"), "test%24file.text");
+        assert_eq!(encode_key("AC/DC - Back in Black/01 Hells Bells.flac"), "AC/DC%20-%20Back%20in%20Black/01%20Hells%20Bells.flac");
+        assert_eq!(encode_key("Björk/a+b~c_d"), "Bj%C3%B6rk/a%2Bb~c_d");
+        assert_eq!(encode_segment("100%"), "100%25");
+    }
+}
@@ -0,0 +1,272 @@
+use std::path::Path;
+
+use anyhow::Result;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::{encode_key, key, Storage};
+
+/// Where and how to reach a WebDAV server, like Nextcloud or Apache's mod_dav.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebDavConfig {
+    /// The url of the collection the files go into, for example
+    /// `https://cloud.example.com/remote.php/dav/files/me/`.
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl WebDavConfig {
+    pub fn new(url: &str) -> Self {
+        WebDavConfig {
+            url: url.to_string(),
+            username: None,
+            password: None,
+        }
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.username = Some(username.to_string());
+        self.password = Some(password.to_string());
+        self
+    }
+}
+
+// Keeps the password out of logs
+impl std::fmt::Debug for WebDavConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebDavConfig")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Files on a WebDAV server, missing collections are created on the way.
+#[derive(Debug, Clone)]
+pub struct WebDavStorage {
+    config: WebDavConfig,
+    base: Url,
+    client: reqwest::Client,
+}
+
+impl WebDavStorage {
+    pub fn new(config: WebDavConfig) -> Result<Self> {
+        let mut base = Url::parse(&config.url)?;
+        // Without the trailing slash joining would replace the last segment
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+
+        Ok(WebDavStorage {
+            config,
+            base,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    fn url(&self, key: &str) -> Result<Url> {
+        Ok(self.base.join(&encode_key(key))?)
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.config.username {
+            Some(username) => request.basic_auth(username, self.config.password.as_deref()),
+            None => request,
+        }
+    }
+
+    /// Creates the collections the file at `key` goes into.
+    async fn create_parents(&self, key: &str) -> Result<()> {
+        let segments: Vec<&str> = key.split('/').collect();
+        for depth in 1..segments.len() {
+            let collection = format!("{}/", encode_key(&segments[..depth].join("/")));
+            let url = self.base.join(&collection)?;
+
+            let response = self.request(Method::from_bytes(b"MKCOL")?, url).send().await?;
+            // 405 means the collection exists already
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check(response, key).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for WebDavStorage {
+    async fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let key = key(path)?;
+        self.create_parents(&key).await?;
+
+        let response = self.request(Method::PUT, self.url(&key)?).body(content.to_vec()).send().await?;
+        check(response, &key).await?;
+        Ok(())
+    }
+
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let key = key(path)?;
+        let response = self.request(Method::GET, self.url(&key)?).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(check(response, &key).await?.bytes().await?.to_vec()))
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        let key = key(path)?;
+        let response = self.request(Method::HEAD, self.url(&key)?).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check(response, &key).await?;
+        Ok(true)
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        let key = key(path)?;
+        let response = self.request(Method::DELETE, self.url(&key)?).send().await?;
+        if response.status() != StatusCode::NOT_FOUND {
+            check(response, &key).await?;
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let from = key(from)?;
+        let to = key(to)?;
+        self.create_parents(&to).await?;
+
+        let response = self
+            .request(Method::from_bytes(b"MOVE")?, self.url(&from)?)
+            .header("Destination", self.url(&to)?.as_str())
+            .header("Overwrite", "T")
+            .send()
+            .await?;
+        check(response, &from).await?;
+        Ok(())
+    }
+}
+
+/// Turns an unsuccessful response into an error.
+async fn check(response: reqwest::Response, key: &str) -> Result<reqwest::Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(anyhow::anyhow!("WebDAV request for {} failed with {}: {}", key, status, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// The method, path and `Destination` header of a request the server got.
+    type Request = (String, String, Option<String>);
+
+    /// Serves one request per connection, answering MKCOL of `existing` collections with 405
+    /// and everything else with 201.
+    async fn server(existing: &'static [&'static str]) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dav/me", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = Arc::clone(&requests);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_string();
+                let path = parts.next().unwrap_or_default().to_string();
+
+                let mut length = 0;
+                let mut destination = None;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(':').unwrap();
+                    match name.to_lowercase().as_str() {
+                        "content-length" => length = value.trim().parse().unwrap(),
+                        "destination" => destination = Some(value.trim().to_string()),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+
+                let status = if method == "MKCOL" && existing.contains(&path.as_str()) {
+                    "405 Method Not Allowed"
+                } else {
+                    "201 Created"
+                };
+                recorded.lock().unwrap().push((method, path, destination));
+
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn request(method: &str, path: &str) -> Request {
+        (method.to_string(), path.to_string(), None)
+    }
+
+    #[tokio::test]
+    async fn write_creates_missing_collections() {
+        let (url, requests) = server(&["/dav/me/Music/"]).await;
+        let storage = WebDavStorage::new(WebDavConfig::new(&url)).unwrap();
+
+        storage
+            .write(Path::new("Music/AC DC/Hells Bells.flac"), b"audio")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                request("MKCOL", "/dav/me/Music/"),
+                request("MKCOL", "/dav/me/Music/AC%20DC/"),
+                request("PUT", "/dav/me/Music/AC%20DC/Hells%20Bells.flac"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_moves_into_the_new_collection() {
+        let (url, requests) = server(&[]).await;
+        let storage = WebDavStorage::new(WebDavConfig::new(&url)).unwrap();
+
+        storage
+            .rename(Path::new("Music/01 Track.flac"), Path::new("Archive/Track+1.flac"))
+            .await
+            .unwrap();
+
+        let destination = format!("{}/Archive/Track%2B1.flac", url);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                request("MKCOL", "/dav/me/Archive/"),
+                ("MOVE".to_string(), "/dav/me/Music/01%20Track.flac".to_string(), Some(destination)),
+            ]
+        );
+    }
+}
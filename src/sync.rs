@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use librespot::core::spotify_id::SpotifyId;
+use serde::Serialize;
+
+use crate::download::{DownloadOptions, Downloader};
+use crate::manifest::ManifestSource;
+use crate::report::TrackStatus;
+use crate::track::Track;
+
+pub const ARCHIVE_FOLDER_NAME: &str = ".archive";
+
+/// What happens to tracks which are no longer part of the synced playlist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncRemoval {
+    #[default]
+    Delete,
+    /// Move the files into `.archive` inside the output folder, keeping their relative path.
+    Archive,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncSummary {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub archived: Vec<PathBuf>,
+    /// Files which were renamed because their position in the playlist changed.
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    pub unchanged: usize,
+    /// Additions which were cancelled before they finished downloading.
+    pub cancelled: usize,
+    /// Additions which were given up on.
+    pub failed: usize,
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} added, {} removed, {} archived, {} renamed, {} unchanged, {} cancelled, {} failed",
+            self.added.len(),
+            self.removed.len(),
+            self.archived.len(),
+            self.renamed.len(),
+            self.unchanged,
+            self.cancelled,
+            self.failed
+        )?;
+
+        for path in &self.added {
+            writeln!(f, "+ {}", path.display())?;
+        }
+        for path in &self.removed {
+            writeln!(f, "- {}", path.display())?;
+        }
+        for path in &self.archived {
+            writeln!(f, "> {}", path.display())?;
+        }
+        for (from, to) in &self.renamed {
+            writeln!(f, "~ {} -> {}", from.display(), to.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Mirrors the playlist into its output folder, downloading the tracks which were added
+/// and removing the ones which left the playlist since the last sync.
+pub async fn sync_playlist(
+    downloader: &Downloader<'_>,
+    playlist_id: SpotifyId,
+    tracks: Vec<Track>,
+    options: &DownloadOptions,
+    removal: SyncRemoval,
+) -> Result<SyncSummary> {
+    let summary = sync_tracks(downloader, playlist_id, tracks, options, removal).await;
+    downloader.close_archive(summary, options).await
+}
+
+async fn sync_tracks(
+    downloader: &Downloader<'_>,
+    playlist_id: SpotifyId,
+    tracks: Vec<Track>,
+    options: &DownloadOptions,
+    removal: SyncRemoval,
+) -> Result<SyncSummary> {
+    if options.dry_run {
+        return Err(anyhow::anyhow!("Syncing a playlist doesn't support dry runs"));
+    }
+
+    let source = ManifestSource::playlist(playlist_id)?;
+    let existing = downloader.manifest().lock().await.entries_from(&source);
+    let storage = downloader.storage();
+
+    let mut summary = SyncSummary::default();
+    let mut additions = Vec::new();
+    // The tracks which are in place already, they only end up in the archive
+    let mut in_place = Vec::new();
+    let wanted: HashSet<SpotifyId> = tracks.iter().map(|track| track.id).collect();
+
+    // Planning the whole playlist keeps the paths of colliding tracks stable between syncs
+    let planned = downloader.plan_tracks(tracks, options).await?;
+    for planned in &planned {
+        let track = &planned.track;
+        let expected = planned.path.clone();
+
+        let mut current = None;
+        if let Some((_, entry)) = existing.iter().find(|(id, entry)| *id == track.id && entry.format == options.format) {
+            for path in &entry.paths {
+                if storage.exists(path).await? {
+                    current = Some(path);
+                    break;
+                }
+            }
+        }
+
+        match current {
+            Some(current) if *current == expected => {
+                summary.unchanged += 1;
+                in_place.push(expected);
+            }
+            Some(current) if !storage.exists(&expected).await? => {
+                tracing::info!("Renaming {:?} to {:?}", current, expected);
+                storage.rename(current, &expected).await?;
+
+                let mut manifest = downloader.manifest().lock().await;
+                if let Some(entry) = manifest.get_mut(&track.id, Some(&source), options.format) {
+                    for path in entry.paths.iter_mut().filter(|path| *path == current) {
+                        *path = expected.clone();
+                    }
+                }
+
+                summary.renamed.push((current.clone(), expected.clone()));
+                in_place.push(expected);
+            }
+            Some(current) => {
+                tracing::warn!("Not renaming {:?}, {:?} already exists", current, expected);
+                summary.unchanged += 1;
+            }
+            None => additions.push(planned.clone()),
+        }
+    }
+
+    for (id, entry) in &existing {
+        // Files of the extra formats stay along with the main one
+        let stale_format = !options.formats().any(|format| format == entry.format);
+        if wanted.contains(id) && !stale_format {
+            continue;
+        }
+
+        for path in &entry.paths {
+            if !storage.exists(path).await? {
+                continue;
+            }
+
+            match removal {
+                SyncRemoval::Delete => {
+                    tracing::info!("Removing {:?}", path);
+                    storage.remove(path).await?;
+                    summary.removed.push(path.clone());
+                }
+                SyncRemoval::Archive => {
+                    let archived = archive_path(&options.destination, path);
+                    tracing::info!("Archiving {:?} to {:?}", path, archived);
+                    storage.rename(path, &archived).await?;
+                    summary.archived.push(archived);
+                }
+            }
+        }
+
+        downloader.manifest().lock().await.remove(id, Some(&source), entry.format);
+    }
+
+    downloader.manifest().lock().await.save().await?;
+
+    let report = downloader.download_planned(additions, options).await?;
+    summary.added = report
+        .with_status(TrackStatus::Downloaded)
+        .filter_map(|track| track.path.clone())
+        .collect();
+    summary.cancelled = report.with_status(TrackStatus::Cancelled).count();
+    summary.failed = report.failed().count();
+
+    downloader.write_playlist_files(&planned, options).await?;
+    downloader.finish_archive(&planned, in_place, &report, options).await?;
+    downloader.run_job_hooks(&report, options).await?;
+
+    Ok(summary)
+}
+
+fn archive_path(destination: &Path, path: &Path) -> PathBuf {
+    let relative = path
+        .strip_prefix(destination)
+        .unwrap_or_else(|_| Path::new(path.file_name().unwrap_or_default()));
+
+    destination.join(ARCHIVE_FOLDER_NAME).join(relative)
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::encoder::PARTIAL_EXTENSION;
+use crate::sanitize::Sanitizer;
+
+/// Templates for the output paths, relative to the destination folder.
+///
+/// Fields are written as `{field}` or `{field:03}` to zero pad numbers to the given width,
+/// and `/` separates folders. Sections in square brackets, like `[ ({year})]`, are left out
+/// when one of their fields is missing.
+///
+/// Available fields: `title`, `artist`, `artists`, `album`, `album_artist`, `album_artists`,
+/// `year`, `disc`, `track`, `id`, `album_id`, `position`, `collection`, `playlist`,
+/// `playlist_owner`, `playlist_id` and `show`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathTemplates {
+    /// Tracks which were requested on their own.
+    pub track: String,
+    pub album: String,
+    pub playlist: String,
+    pub episode: String,
+    /// Albums written as a single file, rendered with the values of the album's first track.
+    pub album_file: String,
+}
+
+impl Default for PathTemplates {
+    fn default() -> Self {
+        PathTemplates {
+            track: "{artists} - {title} - {id}".to_string(),
+            album: "{album_artists} - {album} - {album_id}/{artists} - {title} - {id}".to_string(),
+            playlist: "{playlist_owner} - {playlist} - {playlist_id}/{artists} - {title} - {id}".to_string(),
+            episode: "{show}/{title} - {id}".to_string(),
+            album_file: "{album_artists} - {album} - {album_id}/{album_artists} - {album}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TemplateValues {
+    values: HashMap<&'static str, String>,
+}
+
+impl TemplateValues {
+    pub fn new() -> Self {
+        TemplateValues::default()
+    }
+
+    /// Sets a field, empty values count as missing.
+    pub fn insert<V: ToString>(&mut self, field: &'static str, value: V) {
+        let value = value.to_string();
+        if !value.is_empty() {
+            self.values.insert(field, value);
+        }
+    }
+
+    pub fn insert_opt<V: ToString>(&mut self, field: &'static str, value: Option<V>) {
+        if let Some(value) = value {
+            self.insert(field, value);
+        }
+    }
+
+    fn get(&self, field: &str) -> Option<&str> {
+        self.values.get(field).map(String::as_str)
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Field { name: String, width: Option<usize> },
+    Section(Vec<Segment>),
+}
+
+fn parse(template: &str) -> Result<Vec<Segment>> {
+    parse_segments(&mut template.chars(), false)
+}
+
+fn parse_segments(chars: &mut std::str::Chars, in_section: bool) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => field.push(c),
+                        None => return Err(anyhow::anyhow!("Unclosed field in path template")),
+                    }
+                }
+
+                let (name, width) = match field.split_once(':') {
+                    Some((name, width)) => (
+                        name.to_string(),
+                        Some(width.parse().map_err(|_| {
+                            anyhow::anyhow!("Invalid width for {} in path template", name)
+                        })?),
+                    ),
+                    None => (field, None),
+                };
+
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                segments.push(Segment::Field { name, width });
+            }
+            '[' => {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                segments.push(Segment::Section(parse_segments(chars, true)?));
+            }
+            ']' if in_section => {
+                segments.push(Segment::Literal(literal));
+                return Ok(segments);
+            }
+            c => literal.push(c),
+        }
+    }
+
+    if in_section {
+        return Err(anyhow::anyhow!("Unclosed section in path template"));
+    }
+
+    segments.push(Segment::Literal(literal));
+    Ok(segments)
+}
+
+/// Renders the segments, returning `None` if a field inside a section is missing.
+fn render_segments(
+    segments: &[Segment],
+    values: &TemplateValues,
+    sanitizer: &Sanitizer,
+    in_section: bool,
+) -> Option<String> {
+    let mut rendered = String::new();
+
+    for segment in segments {
+        match segment {
+            Segment::Literal(literal) => rendered.push_str(literal),
+            Segment::Field { name, width } => match values.get(name) {
+                // Values are cleaned on their own, so a `/` inside a title can't create a folder
+                Some(value) => match width {
+                    Some(width) => rendered.push_str(&format!("{:0>width$}", sanitizer.clean(value), width = width)),
+                    None => rendered.push_str(&sanitizer.clean(value)),
+                },
+                None if in_section => return None,
+                None => tracing::warn!("Path template field {} is missing", name),
+            },
+            Segment::Section(section) => {
+                if let Some(section) = render_segments(section, values, sanitizer, true) {
+                    rendered.push_str(&section);
+                }
+            }
+        }
+    }
+
+    Some(rendered)
+}
+
+/// Renders the template into a path below `destination`, cleaning every path component
+/// with the sanitizer and adding the extension to the file name.
+pub fn render(
+    template: &str,
+    values: &TemplateValues,
+    destination: &Path,
+    extension: &str,
+    sanitizer: &Sanitizer,
+) -> Result<PathBuf> {
+    let segments = parse(template)?;
+    let rendered = render_segments(&segments, values, sanitizer, false).unwrap_or_default();
+
+    let mut components: Vec<&str> = rendered
+        .split('/')
+        .map(str::trim)
+        .filter(|component| !component.is_empty())
+        .collect();
+
+    let file_name = components
+        .pop()
+        .ok_or(anyhow::anyhow!("Path template rendered an empty path"))?;
+    // Leave room for the extension and the `.part` suffix used while writing
+    let file_name = sanitizer.clean_reserving(file_name, extension.len() + PARTIAL_EXTENSION.len() + 2);
+
+    let mut path = destination.to_path_buf();
+    path.extend(components.iter().map(|folder| sanitizer.clean(folder)));
+    // Not using `with_extension`, it would cut off everything after a dot in the file name
+    path.push(format!("{}.{}", file_name, extension));
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::SanitizeProfile;
+
+    fn values() -> TemplateValues {
+        let mut values = TemplateValues::new();
+        values.insert("title", "Song");
+        values.insert("artists", "A & B");
+        values.insert("album", "Album");
+        values.insert("track", 3);
+        values.insert("id", "4uLU6hMCjMI75M1A2tKUQC");
+        // Empty values count as missing
+        values.insert("year", "");
+        values.insert_opt::<String>("disc", None);
+        values
+    }
+
+    fn render_with(template: &str, values: &TemplateValues) -> Result<PathBuf> {
+        render(template, values, Path::new("music"), "flac", &Sanitizer::new(SanitizeProfile::Posix))
+    }
+
+    #[test]
+    fn renders_templates() {
+        let cases = [
+            ("{artists} - {title}", "music/A & B - Song.flac"),
+            ("{track:02} {title}", "music/03 Song.flac"),
+            ("{track:1} {title}", "music/3 Song.flac"),
+            ("{album}/{track:03} - {title}", "music/Album/003 - Song.flac"),
+            // Sections are left out when one of their fields is missing
+            ("{album}[ ({year})]/{title}", "music/Album/Song.flac"),
+            ("{album}[ ({track})]/{title}", "music/Album (3)/Song.flac"),
+            ("{album}[ [{disc}-]{track}]/{title}", "music/Album 3/Song.flac"),
+            ("[{album}[ ({year})]]/{title}", "music/Album/Song.flac"),
+            // Missing fields outside a section are left out, unknown ones as well
+            ("{year} {title}", "music/Song.flac"),
+            ("{titel} - {title}", "music/- Song.flac"),
+            ("{title}[ - {unknown}]", "music/Song.flac"),
+            // Empty folders and surrounding spaces are dropped
+            ("{year}/ {album} //{title}", "music/Album/Song.flac"),
+            ("title.with.dots", "music/title.with.dots.flac"),
+        ];
+
+        for (template, expected) in cases {
+            assert_eq!(render_with(template, &values()).unwrap(), PathBuf::from(expected), "{:?}", template);
+        }
+    }
+
+    #[test]
+    fn cleans_values_on_their_own() {
+        let mut values = values();
+        values.insert("title", "AC/DC: Back in Black?");
+        values.insert("album", "..");
+
+        let sanitizer = Sanitizer::new(SanitizeProfile::Ntfs);
+        let path = render("{album}/{title}", &values, Path::new("music"), "flac", &sanitizer).unwrap();
+        assert_eq!(path, PathBuf::from("music/_/ACDC Back in Black.flac"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let cases = ["{title", "{title}/{album", "[{title}", "{album}]/[{title}", "{track:x} {title}", "", "/", "[{year}]"];
+
+        for template in cases {
+            assert!(render_with(template, &values()).is_err(), "{:?}", template);
+        }
+    }
+
+    #[test]
+    fn leaves_room_for_the_extension() {
+        let mut values = values();
+        values.insert("title", "x".repeat(300));
+
+        let path = render_with("{title}", &values).unwrap();
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(file_name.len() + PARTIAL_EXTENSION.len() + 1, 255);
+    }
+}
@@ -1,12 +1,15 @@
 use anyhow::Result;
 use lazy_static::lazy_static;
 use librespot::core::session::Session;
-use librespot::core::spotify_id::SpotifyId;
+use librespot::core::spotify_id::{FileId, SpotifyAudioType, SpotifyId};
 use librespot::metadata::Metadata;
+use librespot::protocol;
+use protobuf::Message;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 #[async_trait::async_trait]
-trait TrackCollection {
+pub trait TrackCollection {
     async fn get_tracks(&self, session: &Session) -> Vec<Track>;
 }
 
@@ -35,7 +38,7 @@ pub async fn get_tracks(spotify_ids: Vec<String>, session: &Session) -> Result<V
     Ok(tracks)
 }
 
-fn parse_uri_or_url(track: &str) -> Option<SpotifyId> {
+pub fn parse_uri_or_url(track: &str) -> Option<SpotifyId> {
     parse_uri(track).or_else(|| parse_url(track))
 }
 
@@ -55,11 +58,50 @@ fn parse_url(track_url: &str) -> Option<SpotifyId> {
     SpotifyId::from_uri(&uri).ok()
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Track {
+    #[serde(with = "spotify_uri")]
     pub id: SpotifyId,
+    #[serde(with = "spotify_uri::option")]
     pub playlist_id: Option<SpotifyId>,
+    #[serde(with = "spotify_uri::option")]
     pub album_id: Option<SpotifyId>,
+    /// 1-based position of the track in its playlist or album.
+    pub position: Option<usize>,
+}
+
+/// (De)serializes a `SpotifyId` as its spotify uri.
+mod spotify_uri {
+    use librespot::core::spotify_id::SpotifyId;
+    use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(id: &SpotifyId, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&id.to_uri().map_err(S::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SpotifyId, D::Error> {
+        let uri = String::deserialize(deserializer)?;
+        SpotifyId::from_uri(&uri).map_err(|_| D::Error::custom(format!("Invalid spotify uri: {}", uri)))
+    }
+
+    pub mod option {
+        use librespot::core::spotify_id::SpotifyId;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(id: &Option<SpotifyId>, serializer: S) -> Result<S::Ok, S::Error> {
+            match id {
+                Some(id) => super::serialize(id, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SpotifyId>, D::Error> {
+            #[derive(Deserialize)]
+            struct Uri(#[serde(with = "super")] SpotifyId);
+
+            Ok(Option::<Uri>::deserialize(deserializer)?.map(|uri| uri.0))
+        }
+    }
 }
 
 lazy_static! {
@@ -71,24 +113,29 @@ impl Track {
     #[allow(dead_code)]
     pub fn new(track: &str) -> Result<Self> {
         let id = parse_uri_or_url(track).ok_or(anyhow::anyhow!("Invalid track"))?;
-        Ok(Track { id, playlist_id: None, album_id: None })
+        Ok(Track { id, playlist_id: None, album_id: None, position: None })
     }
 
     pub fn from_id(id: SpotifyId) -> Self {
-        Track { id, playlist_id: None, album_id: None }
+        Track { id, playlist_id: None, album_id: None, position: None }
     }
 
-    pub fn from_playlist(id: SpotifyId, playlist_id: SpotifyId) -> Self {
-        Track { id, playlist_id: Some(playlist_id), album_id: None }
+    pub fn from_playlist(id: SpotifyId, playlist_id: SpotifyId, position: usize) -> Self {
+        Track { id, playlist_id: Some(playlist_id), album_id: None, position: Some(position) }
     }
 
-    pub fn from_album(id: SpotifyId, album_id: SpotifyId) -> Self {
-        Track { id, playlist_id: None, album_id: Some(album_id) }
-    } 
+    pub fn from_album(id: SpotifyId, album_id: SpotifyId, position: usize) -> Self {
+        Track { id, playlist_id: None, album_id: Some(album_id), position: Some(position) }
+    }
 
     pub async fn metadata(&self, session: &Session) -> Result<TrackMetadata> {
-        let metadata = librespot::metadata::Track::get(session, self.id)
-            .await
+        if self.id.audio_type == SpotifyAudioType::Podcast {
+            return self.episode_metadata(session).await;
+        }
+
+        // The raw message carries details librespot doesn't expose, like track and disc numbers
+        let message = self.track_message(session).await?;
+        let metadata = librespot::metadata::Track::parse(&message, session)
             .map_err(|_| anyhow::anyhow!("Failed to get metadata"))?;
 
         let mut artists = Vec::new();
@@ -104,7 +151,34 @@ impl Track {
             .await
             .map_err(|_| anyhow::anyhow!("Failed to get album"))?;
 
-        Ok(TrackMetadata::from(metadata, artists, album))
+        Ok(TrackMetadata::from(metadata, artists, album, &message))
+    }
+
+    async fn track_message(&self, session: &Session) -> Result<protocol::metadata::Track> {
+        let uri = librespot::metadata::Track::request_url(self.id)?;
+        let response = session
+            .mercury()
+            .get(uri)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get metadata"))?;
+        let payload = response
+            .payload
+            .first()
+            .ok_or(anyhow::anyhow!("Failed to get metadata"))?;
+
+        Ok(protocol::metadata::Track::parse_from_bytes(payload)?)
+    }
+
+    async fn episode_metadata(&self, session: &Session) -> Result<TrackMetadata> {
+        let episode = librespot::metadata::Episode::get(session, self.id)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get episode"))?;
+
+        let show = librespot::metadata::Show::get(session, episode.show)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get show"))?;
+
+        Ok(TrackMetadata::from_episode(episode, show))
     }
 }
 
@@ -144,7 +218,8 @@ impl TrackCollection for Album {
         album
             .tracks
             .iter()
-            .map(|track| Track::from_album(*track, self.id))
+            .enumerate()
+            .map(|(i, track)| Track::from_album(*track, self.id, i + 1))
             .collect()
     }
 }
@@ -181,7 +256,8 @@ impl TrackCollection for Playlist {
         playlist
             .tracks
             .iter()
-            .map(|track| Track::from_playlist(*track, self.id))
+            .enumerate()
+            .map(|(i, track)| Track::from_playlist(*track, self.id, i + 1))
             .collect()
     }
 }
@@ -190,9 +266,10 @@ impl TrackCollection for Playlist {
 pub struct TrackMetadata {
     pub artists: Vec<ArtistMetadata>,
     pub track_name: String,
-    #[allow(dead_code)]
     pub album: AlbumMetadata,
     pub duration: i32,
+    pub number: Option<u32>,
+    pub disc_number: Option<u32>,
 }
 
 impl TrackMetadata {
@@ -200,18 +277,55 @@ impl TrackMetadata {
         track: librespot::metadata::Track,
         artists: Vec<librespot::metadata::Artist>,
         album: librespot::metadata::Album,
+        message: &protocol::metadata::Track,
     ) -> Self {
         let artists = artists
             .iter()
             .map(|artist| ArtistMetadata::from(artist.clone()))
             .collect();
-        let album = AlbumMetadata::from(album);
+
+        let album_message = message.get_album();
+        let album = AlbumMetadata {
+            id: Some(album.id),
+            name: album.name.clone(),
+            artists: album_message
+                .get_artist()
+                .iter()
+                .map(|artist| ArtistMetadata { name: artist.get_name().to_string() })
+                .collect(),
+            year: album_message
+                .has_date()
+                .then(|| album_message.get_date().get_year()),
+            cover: largest_cover(album_message.get_cover_group().get_image()).or(album.covers.last().copied()),
+        };
 
         TrackMetadata {
             artists,
             track_name: track.name.clone(),
             album,
             duration: track.duration,
+            number: message.has_number().then(|| message.get_number() as u32),
+            disc_number: message.has_disc_number().then(|| message.get_disc_number() as u32),
+        }
+    }
+
+    /// Maps an episode onto track metadata, with the show as its album and the publisher as its artist.
+    pub fn from_episode(episode: librespot::metadata::Episode, show: librespot::metadata::Show) -> Self {
+        let publisher = ArtistMetadata { name: show.publisher.clone() };
+
+        TrackMetadata {
+            artists: vec![publisher.clone()],
+            track_name: episode.name.clone(),
+            album: AlbumMetadata {
+                id: None,
+                name: show.name.clone(),
+                artists: vec![publisher],
+                year: None,
+                cover: None,
+            },
+            duration: episode.duration,
+            number: None,
+            disc_number: None,
         }
     }
 }
@@ -231,14 +345,29 @@ impl From<librespot::metadata::Artist> for ArtistMetadata {
 
 #[derive(Clone, Debug)]
 pub struct AlbumMetadata {
-    #[allow(dead_code)]
+    pub id: Option<SpotifyId>,
     pub name: String,
+    pub artists: Vec<ArtistMetadata>,
+    pub year: Option<i32>,
+    /// The largest cover image of the album.
+    pub cover: Option<FileId>,
 }
 
-impl From<librespot::metadata::Album> for AlbumMetadata {
-    fn from(album: librespot::metadata::Album) -> Self {
-        AlbumMetadata {
-            name: album.name.clone(),
-        }
-    }
+fn largest_cover(images: &[protocol::metadata::Image]) -> Option<FileId> {
+    use protocol::metadata::Image_Size;
+
+    images
+        .iter()
+        .filter(|image| image.get_file_id().len() == 20)
+        .max_by_key(|image| match image.get_size() {
+            Image_Size::SMALL => 0,
+            Image_Size::DEFAULT => 1,
+            Image_Size::LARGE => 2,
+            Image_Size::XLARGE => 3,
+        })
+        .map(|image| {
+            let mut id = [0u8; 20];
+            id.copy_from_slice(image.get_file_id());
+            FileId(id)
+        })
 }
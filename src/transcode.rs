@@ -0,0 +1,630 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::StreamExt;
+use id3::frame::Content;
+use librespot::core::spotify_id::SpotifyId;
+use metaflac::block::{Block, BlockType, CueSheet, PictureType as FlacPictureType};
+use metaflac::Tag as FlacTag;
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::encoder::{EncoderSettings, Format, Samples};
+use crate::manifest::{Manifest, ManifestEntry, MANIFEST_FILE_NAME};
+use crate::storage::Storage;
+
+/// Vorbis comments and the ID3 text frames they map to.
+const TEXT_FRAMES: [(&str, &str); 9] = [
+    ("TITLE", "TIT2"),
+    ("ARTIST", "TPE1"),
+    ("ALBUM", "TALB"),
+    ("ALBUMARTIST", "TPE2"),
+    ("DATE", "TDRC"),
+    ("TRACKNUMBER", "TRCK"),
+    ("DISCNUMBER", "TPOS"),
+    ("GENRE", "TCON"),
+    ("COMPOSER", "TCOM"),
+];
+
+/// The FLAC picture types by their number, which ID3 shares.
+const FLAC_PICTURE_TYPES: [FlacPictureType; 21] = [
+    FlacPictureType::Other,
+    FlacPictureType::Icon,
+    FlacPictureType::OtherIcon,
+    FlacPictureType::CoverFront,
+    FlacPictureType::CoverBack,
+    FlacPictureType::Leaflet,
+    FlacPictureType::Media,
+    FlacPictureType::LeadArtist,
+    FlacPictureType::Artist,
+    FlacPictureType::Conductor,
+    FlacPictureType::Band,
+    FlacPictureType::Composer,
+    FlacPictureType::Lyricist,
+    FlacPictureType::RecordingLocation,
+    FlacPictureType::DuringRecording,
+    FlacPictureType::DuringPerformance,
+    FlacPictureType::ScreenCapture,
+    FlacPictureType::BrightFish,
+    FlacPictureType::Illustration,
+    FlacPictureType::BandLogo,
+    FlacPictureType::PublisherLogo,
+];
+
+/// Where the files to transcode come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscodeSource {
+    /// Every file recorded in the library's manifest. The new tree gets a manifest of its own.
+    #[default]
+    Manifest,
+    /// Every FLAC and MP3 file below the library folder, hidden folders left out. Only works
+    /// on the local file system.
+    Scan,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranscodeOptions {
+    /// The output folder of the existing library.
+    pub library: PathBuf,
+    /// The root of the new tree, files keep their path relative to `library`.
+    pub destination: PathBuf,
+    pub source: TranscodeSource,
+    pub format: Format,
+    pub settings: EncoderSettings,
+    pub parallel: usize,
+    /// Transcodes files again whose target exists already.
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+impl TranscodeOptions {
+    pub fn new(library: &str, destination: &str, format: Format) -> Self {
+        TranscodeOptions {
+            library: PathBuf::from(library),
+            destination: PathBuf::from(destination),
+            source: TranscodeSource::default(),
+            format,
+            settings: EncoderSettings::new(format, None),
+            parallel: 4,
+            overwrite: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TranscodeSummary {
+    /// Every transcoded file with the file it was written to.
+    pub transcoded: Vec<(PathBuf, PathBuf)>,
+    /// Files whose target existed already, or was written from another format of the same track.
+    pub skipped: Vec<PathBuf>,
+    /// Files which couldn't be transcoded, with the reason.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl fmt::Display for TranscodeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} transcoded, {} skipped, {} failed",
+            self.transcoded.len(),
+            self.skipped.len(),
+            self.failed.len()
+        )?;
+
+        for (from, to) in &self.transcoded {
+            writeln!(f, "~ {} -> {}", from.display(), to.display())?;
+        }
+        for (path, reason) in &self.failed {
+            writeln!(f, "! {}: {}", path.display(), reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// A file of the library with the manifest entries of the tracks in it. Albums written as a
+/// single file hold several, scanned files none.
+struct SourceFile {
+    path: PathBuf,
+    entries: Vec<(SpotifyId, ManifestEntry)>,
+}
+
+enum Outcome {
+    /// The target path and the manifest entries of the new file.
+    Transcoded(PathBuf, Vec<(SpotifyId, ManifestEntry)>),
+    Skipped,
+}
+
+/// Tags which can be carried from one format to the other.
+#[derive(Debug, Default)]
+struct Tags {
+    /// Vorbis comment names in upper case with their values.
+    fields: Vec<(String, Vec<String>)>,
+    pictures: Vec<Picture>,
+    /// The track offsets of an album written as a single file.
+    cue_sheet: Option<CueSheet>,
+}
+
+#[derive(Debug)]
+struct Picture {
+    /// The picture type as numbered by FLAC and ID3.
+    picture_type: u8,
+    mime_type: String,
+    description: String,
+    data: Vec<u8>,
+}
+
+/// Re-encodes the FLAC and MP3 files of an existing library into a new tree in another
+/// format, carrying their tags and embedded pictures across. Reading and writing goes
+/// through `storage`, no session is needed.
+///
+/// A file which fails doesn't stop the others, it ends up in `TranscodeSummary::failed`.
+pub async fn transcode(storage: Arc<dyn Storage>, options: &TranscodeOptions) -> Result<TranscodeSummary> {
+    if crate::encoder::get_encoder(options.format).is_none() {
+        return Err(anyhow::anyhow!("Files can't be transcoded to {}", options.format.extension()));
+    }
+
+    let files = match options.source {
+        TranscodeSource::Manifest => manifest_files(Arc::clone(&storage), options).await?,
+        TranscodeSource::Scan => {
+            if !storage.is_local() {
+                return Err(anyhow::anyhow!("Only libraries on the local file system can be scanned"));
+            }
+            scan_files(&options.library, &options.destination).await?
+        }
+    };
+    let files_storage = storage.as_ref();
+    let (files, duplicates) = unique_targets(files_storage, files, options);
+    tracing::info!("Transcoding {} files to {}", files.len(), options.format.extension());
+
+    let outcomes = futures::stream::iter(files)
+        .map(|file| async move {
+            let outcome = transcode_file(files_storage, &file, options).await;
+            (file.path, outcome)
+        })
+        .buffer_unordered(options.parallel.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut summary = TranscodeSummary {
+        skipped: duplicates,
+        ..Default::default()
+    };
+    let mut entries = Vec::new();
+    for (path, outcome) in outcomes {
+        match outcome {
+            Ok(Outcome::Transcoded(target, transcoded)) => {
+                summary.transcoded.push((path, target));
+                entries.extend(transcoded);
+            }
+            Ok(Outcome::Skipped) => summary.skipped.push(path),
+            Err(e) => {
+                tracing::warn!("Failed to transcode {:?}: {}", path, e);
+                summary.failed.push((path, e.to_string()));
+            }
+        }
+    }
+    summary.transcoded.sort();
+    summary.skipped.sort();
+    summary.failed.sort();
+
+    if !entries.is_empty() {
+        let mut manifest = Manifest::load(storage, options.destination.join(MANIFEST_FILE_NAME)).await?;
+        for (id, entry) in entries {
+            manifest.insert(&id, entry)?;
+        }
+        manifest.save().await?;
+    }
+
+    Ok(summary)
+}
+
+/// The files recorded in the library's manifest, every file once.
+async fn manifest_files(storage: Arc<dyn Storage>, options: &TranscodeOptions) -> Result<Vec<SourceFile>> {
+    let manifest = Manifest::load(storage, options.library.join(MANIFEST_FILE_NAME)).await?;
+
+    let mut files: BTreeMap<PathBuf, Vec<(SpotifyId, ManifestEntry)>> = BTreeMap::new();
+    for (id, entry) in manifest.entries() {
+        for path in &entry.paths {
+            files.entry(path.clone()).or_default().push((id, entry.clone()));
+        }
+    }
+
+    Ok(files
+        .into_iter()
+        .map(|(path, entries)| SourceFile { path, entries })
+        .collect())
+}
+
+/// The FLAC and MP3 files below `library`, leaving out hidden folders like the sync archive
+/// and the new tree if it's inside the library.
+async fn scan_files(library: &Path, destination: &Path) -> Result<Vec<SourceFile>> {
+    let mut files = Vec::new();
+    let mut folders = vec![library.to_path_buf()];
+
+    while let Some(folder) = folders.pop() {
+        let mut dir = tokio::fs::read_dir(&folder).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') || path.starts_with(destination) {
+                continue;
+            }
+
+            if entry.file_type().await?.is_dir() {
+                folders.push(path);
+            } else if container(&path).is_some() {
+                files.push(SourceFile { path, entries: Vec::new() });
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Keeps one file per target, so the formats of a track written in several don't race for the
+/// same target. Lossless files are preferred, then the first by path, the others are returned
+/// as skipped.
+fn unique_targets(storage: &dyn Storage, files: Vec<SourceFile>, options: &TranscodeOptions) -> (Vec<SourceFile>, Vec<PathBuf>) {
+    let lossy = |file: &SourceFile| container(&file.path) != Some("flac");
+
+    let mut unique = Vec::new();
+    let mut targets: BTreeMap<PathBuf, SourceFile> = BTreeMap::new();
+    let mut skipped = Vec::new();
+    for file in files {
+        // Files without a target fail when they are transcoded
+        let Ok(target) = target_path(storage, &file.path, options) else {
+            unique.push(file);
+            continue;
+        };
+
+        match targets.entry(target) {
+            Entry::Vacant(entry) => {
+                entry.insert(file);
+            }
+            Entry::Occupied(mut entry) => {
+                let duplicate = if lossy(entry.get()) && !lossy(&file) {
+                    entry.insert(file)
+                } else {
+                    file
+                };
+                tracing::info!("Skipping {:?}, {:?} is transcoded to {:?} instead", duplicate.path, entry.get().path, entry.key());
+                skipped.push(duplicate.path);
+            }
+        }
+    }
+
+    unique.extend(targets.into_values());
+    (unique, skipped)
+}
+
+/// The path the file is transcoded to.
+fn target_path(storage: &dyn Storage, path: &Path, options: &TranscodeOptions) -> Result<PathBuf> {
+    let relative = relative_path(storage, path, &options.library)?;
+    Ok(options.destination.join(relative).with_extension(options.format.extension()))
+}
+
+/// The extension of a file the library can decode, in lower case.
+fn container(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "flac" => Some("flac"),
+        "mp3" => Some("mp3"),
+        _ => None,
+    }
+}
+
+async fn transcode_file(storage: &dyn Storage, file: &SourceFile, options: &TranscodeOptions) -> Result<Outcome> {
+    let container = container(&file.path).ok_or(anyhow::anyhow!("Only FLAC and MP3 files can be transcoded"))?;
+
+    let target = target_path(storage, &file.path, options)?;
+
+    if !options.overwrite && storage.exists(&target).await? {
+        tracing::info!("Skipping {:?}, {:?} exists already", file.path, target);
+        return Ok(Outcome::Skipped);
+    }
+
+    let content = storage
+        .read(&file.path)
+        .await?
+        .ok_or(anyhow::anyhow!("The file doesn't exist"))?;
+    let tags = match container {
+        "flac" => flac_tags(&content)?,
+        _ => id3_tags(&content)?,
+    };
+
+    // The external cue sheet of a single file album points to the album file by its name,
+    // which only stays the same for FLAC
+    let cue_path = file.path.with_extension("cue");
+    let cue_file = storage.read(&cue_path).await?;
+    if (tags.cue_sheet.is_some() || cue_file.is_some()) && options.format != Format::Flac {
+        return Err(anyhow::anyhow!("Albums written as a single file can only be transcoded to flac"));
+    }
+
+    tracing::info!("Transcoding {:?} to {:?}", file.path, target);
+    let samples = tokio::task::spawn_blocking(move || decode(content, container)).await??;
+    let encoder = crate::encoder::get_encoder(options.format)
+        .ok_or(anyhow::anyhow!("Files can't be transcoded to {}", options.format.extension()))?;
+    let stream = encoder.encode(samples, options.settings).await?;
+    let stream = tag(options.format, &tags, stream.stream)?;
+
+    storage.write(&target, &stream).await?;
+    if let Some(cue_file) = cue_file {
+        storage.write(&target.with_extension("cue"), &cue_file).await?;
+    }
+
+    let entries = file
+        .entries
+        .iter()
+        .map(|(id, entry)| {
+            let mut transcoded = ManifestEntry::new(
+                target.clone(),
+                options.format,
+                options.settings,
+                entry.source_quality.unwrap_or_default(),
+                &stream,
+                entry.source.clone(),
+            );
+            transcoded.source_quality = entry.source_quality;
+            (*id, transcoded)
+        })
+        .collect();
+
+    Ok(Outcome::Transcoded(target, entries))
+}
+
+/// The path of the file relative to the library. Both are normalised first, so `./music` and
+/// `music` match, and made absolute on the local file system.
+fn relative_path(storage: &dyn Storage, path: &Path, library: &Path) -> Result<PathBuf> {
+    let normalise = |path: &Path| -> Result<PathBuf> {
+        let path = if storage.is_local() {
+            std::path::absolute(path)?
+        } else {
+            path.to_path_buf()
+        };
+
+        let mut normalised = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir if normalised.file_name().is_some() => {
+                    normalised.pop();
+                }
+                component => normalised.push(component),
+            }
+        }
+        Ok(normalised)
+    };
+
+    let path = normalise(path)?;
+    let library = normalise(library)?;
+    match path.strip_prefix(&library) {
+        Ok(relative) => Ok(relative.to_path_buf()),
+        Err(_) => Err(anyhow::anyhow!("{:?} isn't inside the library {:?}", path, library)),
+    }
+}
+
+/// Decodes a FLAC or MP3 file into interleaved 16 bit samples.
+fn decode(content: Vec<u8>, container: &str) -> Result<Samples> {
+    let mut hint = Hint::new();
+    hint.with_extension(container);
+    let source = MediaSourceStream::new(Box::new(Cursor::new(content)), Default::default());
+    let probed = symphonia::default::get_probe().format(&hint, source, &FormatOptions::default(), &MetadataOptions::default())?;
+
+    let mut reader = probed.format;
+    let track = reader.default_track().ok_or(anyhow::anyhow!("The file has no audio"))?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = Vec::new();
+    let mut format = None;
+    let mut buffer: Option<SampleBuffer<i16>> = None;
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt frame is left out, like players do
+            Err(DecodeError::DecodeError(e)) => {
+                tracing::warn!("Skipping an undecodable packet: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = *decoded.spec();
+        format.get_or_insert((spec.rate, spec.channels.count() as u32));
+        let buffer = match &mut buffer {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * spec.channels.count() => buffer,
+            _ => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend(buffer.samples().iter().map(|&sample| i32::from(sample)));
+    }
+
+    let (sample_rate, channels) = format.ok_or(anyhow::anyhow!("The file has no audio"))?;
+    Ok(Samples::new(samples, sample_rate, channels, 16))
+}
+
+fn flac_tags(content: &[u8]) -> Result<Tags> {
+    let tag = FlacTag::read_from(&mut Cursor::new(content))?;
+
+    let mut fields: Vec<(String, Vec<String>)> = tag
+        .vorbis_comments()
+        .map(|comments| {
+            comments
+                .comments
+                .iter()
+                .map(|(name, values)| (name.to_uppercase(), values.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+    fields.sort();
+
+    let pictures = tag
+        .pictures()
+        .map(|picture| Picture {
+            picture_type: picture.picture_type as u8,
+            mime_type: picture.mime_type.clone(),
+            description: picture.description.clone(),
+            data: picture.data.clone(),
+        })
+        .collect();
+
+    let cue_sheet = tag.get_blocks(BlockType::CueSheet).find_map(|block| match block {
+        Block::CueSheet(cue_sheet) => Some(cue_sheet.clone()),
+        _ => None,
+    });
+
+    Ok(Tags {
+        fields,
+        pictures,
+        cue_sheet,
+    })
+}
+
+fn id3_tags(content: &[u8]) -> Result<Tags> {
+    let Some(tag) = id3::no_tag_ok(id3::Tag::read_from2(Cursor::new(content)))? else {
+        return Ok(Tags::default());
+    };
+
+    let mut tags = Tags::default();
+    for frame in tag.frames() {
+        match frame.content() {
+            Content::Text(text) => {
+                if let Some((name, _)) = TEXT_FRAMES.iter().find(|(_, id)| *id == frame.id()) {
+                    // ID3v2.4 separates multiple values with a null character
+                    tags.fields.push((name.to_string(), text.split('\0').map(str::to_string).collect()));
+                }
+            }
+            Content::ExtendedText(text) => {
+                tags.fields.push((text.description.to_uppercase(), vec![text.value.clone()]));
+            }
+            Content::Picture(picture) => tags.pictures.push(Picture {
+                picture_type: u8::from(picture.picture_type),
+                mime_type: picture.mime_type.clone(),
+                description: picture.description.clone(),
+                data: picture.data.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Writes the tags into the encoded stream.
+fn tag(format: Format, tags: &Tags, stream: Vec<u8>) -> Result<Vec<u8>> {
+    let mut tagged = Vec::with_capacity(stream.len());
+
+    match format {
+        Format::Flac => {
+            let mut reader = Cursor::new(&stream);
+            let mut tag = FlacTag::read_from(&mut reader)?;
+            for (name, values) in &tags.fields {
+                tag.set_vorbis(name.clone(), values.clone());
+            }
+            for picture in &tags.pictures {
+                let mut block = metaflac::block::Picture::new();
+                block.picture_type = FLAC_PICTURE_TYPES
+                    .get(picture.picture_type as usize)
+                    .copied()
+                    .unwrap_or(FlacPictureType::Other);
+                block.mime_type = picture.mime_type.clone();
+                block.description = picture.description.clone();
+                block.data = picture.data.clone();
+                tag.push_block(Block::Picture(block));
+            }
+            // The offsets stay valid, the sample rate doesn't change
+            if let Some(cue_sheet) = &tags.cue_sheet {
+                tag.remove_blocks(BlockType::CueSheet);
+                tag.push_block(Block::CueSheet(cue_sheet.clone()));
+            }
+
+            tag.write_to(&mut tagged)?;
+            tagged.extend_from_slice(&stream[reader.position() as usize..]);
+        }
+        #[cfg(feature = "mp3")]
+        Format::Mp3 => {
+            use id3::TagLike;
+
+            let mut tag = id3::Tag::new();
+            for (name, values) in &tags.fields {
+                match TEXT_FRAMES.iter().find(|(field, _)| field == name) {
+                    Some((_, id)) => tag.set_text_values(*id, values.iter().cloned()),
+                    None => {
+                        tag.add_frame(id3::frame::ExtendedText {
+                            description: name.clone(),
+                            value: values.join("\0"),
+                        });
+                    }
+                }
+            }
+            for picture in &tags.pictures {
+                tag.add_frame(id3::frame::Picture {
+                    mime_type: picture.mime_type.clone(),
+                    picture_type: id3_picture_type(picture.picture_type),
+                    description: picture.description.clone(),
+                    data: picture.data.clone(),
+                });
+            }
+
+            tag.write_to(&mut tagged, id3::Version::Id3v24)?;
+            tagged.extend_from_slice(&stream);
+        }
+        Format::Ogg => return Err(anyhow::anyhow!("Files can't be transcoded to ogg")),
+    }
+
+    Ok(tagged)
+}
+
+#[cfg(feature = "mp3")]
+fn id3_picture_type(picture_type: u8) -> id3::frame::PictureType {
+    use id3::frame::PictureType;
+
+    const TYPES: [PictureType; 21] = [
+        PictureType::Other,
+        PictureType::Icon,
+        PictureType::OtherIcon,
+        PictureType::CoverFront,
+        PictureType::CoverBack,
+        PictureType::Leaflet,
+        PictureType::Media,
+        PictureType::LeadArtist,
+        PictureType::Artist,
+        PictureType::Conductor,
+        PictureType::Band,
+        PictureType::Composer,
+        PictureType::Lyricist,
+        PictureType::RecordingLocation,
+        PictureType::DuringRecording,
+        PictureType::DuringPerformance,
+        PictureType::ScreenCapture,
+        PictureType::BrightFish,
+        PictureType::Illustration,
+        PictureType::BandLogo,
+        PictureType::PublisherLogo,
+    ];
+    TYPES
+        .get(picture_type as usize)
+        .copied()
+        .unwrap_or(PictureType::Undefined(picture_type))
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::track::TrackMetadata;
+
+/// Notices tracks whose player stopped delivering audio, tears them down and retries them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Watchdog {
+    /// A track stalls when no audio arrives for this long, 60 seconds by default.
+    pub stall_timeout: Duration,
+    /// A track times out when it takes longer than this many times its duration plus `grace`.
+    pub duration_factor: u32,
+    pub grace: Duration,
+    /// How often a stalled or timed out track is tried again before it's given up on.
+    pub retries: u32,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            stall_timeout: Duration::from_secs(60),
+            duration_factor: 3,
+            grace: Duration::from_secs(120),
+            retries: 2,
+        }
+    }
+}
+
+impl Watchdog {
+    /// Starts the timers of one attempt at downloading the track.
+    pub fn start(&self, metadata: &TrackMetadata) -> WatchTimer {
+        let duration = Duration::from_millis(metadata.duration.max(0) as u64);
+        let total = duration * self.duration_factor + self.grace;
+        let now = Instant::now();
+
+        WatchTimer {
+            stall_timeout: self.stall_timeout,
+            stall_deadline: now + self.stall_timeout,
+            total,
+            total_deadline: now + total,
+        }
+    }
+}
+
+/// Why an attempt at downloading a track was given up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stall {
+    /// No audio arrived for this long.
+    NoAudio(Duration),
+    /// The track took longer than this in total.
+    TimedOut(Duration),
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stall::NoAudio(after) => write!(f, "no audio arrived for {:?}", after),
+            Stall::TimedOut(after) => write!(f, "the track took longer than {:?}", after),
+        }
+    }
+}
+
+/// The outcome of one attempt at downloading a track.
+pub enum Attempt<T> {
+    Done(T),
+    Cancelled,
+    Stalled(Stall),
+}
+
+/// The outcome of downloading a track over all its attempts.
+pub enum Outcome<T> {
+    Done(T),
+    Cancelled,
+    /// Every attempt stalled, with the reason of the last one.
+    Failed(String),
+}
+
+/// The stall and total deadlines of an attempt.
+pub struct WatchTimer {
+    stall_timeout: Duration,
+    stall_deadline: Instant,
+    total: Duration,
+    total_deadline: Instant,
+}
+
+impl WatchTimer {
+    /// Audio arrived, the stall deadline starts over.
+    pub fn progress(&mut self) {
+        self.stall_deadline = Instant::now() + self.stall_timeout;
+    }
+
+    /// The job is paused, so the missing audio doesn't count: both deadlines move on.
+    pub fn paused(&mut self) {
+        let now = Instant::now();
+        let waited = now.saturating_duration_since(self.stall_deadline - self.stall_timeout);
+        self.total_deadline += waited;
+        self.stall_deadline = now + self.stall_timeout;
+    }
+
+    /// The track was held back by the bandwidth cap for this long, which doesn't count either.
+    pub fn throttled(&mut self, waited: Duration) {
+        self.total_deadline += waited;
+        self.stall_deadline = Instant::now() + self.stall_timeout;
+    }
+
+    /// Completes when either deadline passes.
+    pub async fn expired(&self) -> Stall {
+        if self.total_deadline <= self.stall_deadline {
+            tokio::time::sleep_until(self.total_deadline).await;
+            Stall::TimedOut(self.total)
+        } else {
+            tokio::time::sleep_until(self.stall_deadline).await;
+            Stall::NoAudio(self.stall_timeout)
+        }
+    }
+}